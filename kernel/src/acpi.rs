@@ -0,0 +1,400 @@
+use crate::klog;
+use crate::memory::PHYSICAL_MEMORY_OFFSET;
+use crate::sync::OnceCell;
+use alloc::vec::Vec;
+
+// Tables are read straight out of the physical memory mapping and copied into `AcpiInfo`, so
+// nothing here keeps pointers into firmware memory.
+
+const SDT_HEADER_LEN: u64 = 36;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+const MADT_CPU_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// FADT flag: the reset register is valid.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    // Usable now; firmware also lists CPUs that could only be brought online later.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    // First global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+// An ISA IRQ that is not wired to the GSI of the same number, or not with the ISA default
+// polarity and trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    // MPS INTI flags: bits 0-1 polarity, bits 2-3 trigger mode.
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // 0xFF: all processors.
+    pub processor_id: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+// Generic Address Structure.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    // 0 = system memory, 1 = system I/O.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub dsdt: u64,
+    // SLP_TYPa and SLP_TYPb for soft-off, taken from the \_S5 object of the DSDT.
+    pub s5_sleep_types: Option<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub address: GenericAddress,
+    pub hpet_number: u8,
+    pub min_tick: u16,
+}
+
+#[derive(Debug)]
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub local_apic_address: u64,
+    // MADT flags; bit 0 means legacy 8259 PICs are present as well.
+    pub madt_flags: u32,
+    pub cpus: Vec<Cpu>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+}
+
+impl AcpiInfo {
+    pub fn enabled_cpus(&self) -> impl Iterator<Item = &Cpu> {
+        self.cpus.iter().filter(|cpu| cpu.enabled)
+    }
+
+    // GSI and flags an ISA IRQ is delivered on, honouring the MADT overrides.
+    pub fn isa_irq_route(&self, irq: u8) -> (u32, u16) {
+        self.interrupt_overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .map_or((irq as u32, 0), |o| (o.gsi, o.flags))
+    }
+}
+
+static ACPI: OnceCell<AcpiInfo> = OnceCell::new();
+
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI.get()
+}
+
+fn read<T: Copy>(phys: u64) -> T {
+    unsafe { core::ptr::read_unaligned((PHYSICAL_MEMORY_OFFSET + phys) as *const T) }
+}
+
+fn bytes(phys: u64, len: u64) -> &'static [u8] {
+    unsafe {
+        core::slice::from_raw_parts((PHYSICAL_MEMORY_OFFSET + phys) as *const u8, len as usize)
+    }
+}
+
+fn checksum_ok(phys: u64, len: u64) -> bool {
+    bytes(phys, len)
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        == 0
+}
+
+fn read_gas(phys: u64) -> GenericAddress {
+    GenericAddress {
+        address_space: read(phys),
+        bit_width: read(phys + 1),
+        bit_offset: read(phys + 2),
+        access_size: read(phys + 3),
+        address: read(phys + 4),
+    }
+}
+
+// A system description table at `phys`: its signature and length, once the checksum checks out.
+fn table(phys: u64) -> Option<([u8; 4], u64)> {
+    if phys == 0 {
+        return None;
+    }
+    let signature: [u8; 4] = read(phys);
+    let len = read::<u32>(phys + 4) as u64;
+    if len < SDT_HEADER_LEN || !checksum_ok(phys, len) {
+        klog!(
+            Warn,
+            "ACPI: bad table {:?} at {:#x}",
+            core::str::from_utf8(&signature).unwrap_or("????"),
+            phys
+        );
+        return None;
+    }
+    Some((signature, len))
+}
+
+// Physical addresses of the tables listed in the XSDT, or the RSDT on ACPI 1.0.
+fn root_table_entries(rsdp: u64) -> Option<(u8, Vec<u64>)> {
+    if read::<[u8; 8]>(rsdp) != *b"RSD PTR " || !checksum_ok(rsdp, 20) {
+        return None;
+    }
+    let revision: u8 = read(rsdp + 15);
+
+    let xsdt = if revision >= 2 {
+        let len = read::<u32>(rsdp + 20) as u64;
+        if !checksum_ok(rsdp, len) {
+            return None;
+        }
+        read::<u64>(rsdp + 24)
+    } else {
+        0
+    };
+
+    let (root, entry_size) = if xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (read::<u32>(rsdp + 16) as u64, 4)
+    };
+    let (_, len) = table(root)?;
+
+    let entries = (SDT_HEADER_LEN..len)
+        .step_by(entry_size)
+        .filter(|offset| offset + entry_size as u64 <= len)
+        .map(|offset| {
+            if entry_size == 8 {
+                read::<u64>(root + offset)
+            } else {
+                read::<u32>(root + offset) as u64
+            }
+        })
+        .collect();
+    Some((revision, entries))
+}
+
+fn parse_madt(info: &mut AcpiInfo, madt: u64, len: u64) {
+    info.local_apic_address = read::<u32>(madt + 36) as u64;
+    info.madt_flags = read(madt + 40);
+
+    let mut entry = madt + 44;
+    while entry + 2 <= madt + len {
+        let kind: u8 = read(entry);
+        let entry_len: u8 = read(entry + 1);
+        if entry_len < 2 || entry + entry_len as u64 > madt + len {
+            break;
+        }
+
+        match kind {
+            MADT_LOCAL_APIC => {
+                let flags: u32 = read(entry + 4);
+                if flags & (MADT_CPU_ENABLED | MADT_CPU_ONLINE_CAPABLE) != 0 {
+                    info.cpus.push(Cpu {
+                        processor_id: read::<u8>(entry + 2) as u32,
+                        apic_id: read::<u8>(entry + 3) as u32,
+                        enabled: flags & MADT_CPU_ENABLED != 0,
+                    });
+                }
+            }
+            MADT_LOCAL_X2APIC => {
+                let flags: u32 = read(entry + 8);
+                if flags & (MADT_CPU_ENABLED | MADT_CPU_ONLINE_CAPABLE) != 0 {
+                    info.cpus.push(Cpu {
+                        processor_id: read(entry + 12),
+                        apic_id: read(entry + 4),
+                        enabled: flags & MADT_CPU_ENABLED != 0,
+                    });
+                }
+            }
+            MADT_IO_APIC => info.io_apics.push(IoApic {
+                id: read(entry + 2),
+                address: read::<u32>(entry + 4) as u64,
+                gsi_base: read(entry + 8),
+            }),
+            MADT_INTERRUPT_OVERRIDE => info.interrupt_overrides.push(InterruptOverride {
+                irq: read(entry + 3),
+                gsi: read(entry + 4),
+                flags: read(entry + 8),
+            }),
+            MADT_LOCAL_APIC_NMI => info.local_apic_nmis.push(LocalApicNmi {
+                processor_id: read(entry + 2),
+                flags: read(entry + 3),
+                lint: read(entry + 5),
+            }),
+            MADT_LOCAL_APIC_ADDRESS => info.local_apic_address = read(entry + 4),
+            _ => {}
+        }
+
+        entry += entry_len as u64;
+    }
+}
+
+// Find the \_S5 package in the DSDT's AML and pull out the two SLP_TYP values. A full AML
+// interpreter is overkill for that; the package is a plain constant on every firmware we care
+// about.
+fn find_s5_sleep_types(dsdt: u64) -> Option<(u16, u16)> {
+    let (_, len) = table(dsdt)?;
+    let aml = &bytes(dsdt, len)[SDT_HEADER_LEN as usize..];
+
+    let pos = aml.windows(4).position(|window| window == b"_S5_")?;
+    // NameOp, optionally followed by a root prefix, right before the name.
+    let named = (pos >= 1 && aml[pos - 1] == 0x08)
+        || (pos >= 2 && aml[pos - 2] == 0x08 && aml[pos - 1] == b'\\');
+    if !named {
+        return None;
+    }
+
+    let mut rest = aml.get(pos + 4..)?;
+    // PackageOp, PkgLength (1-4 bytes), NumElements
+    if *rest.first()? != 0x12 {
+        return None;
+    }
+    let pkg_len_bytes = ((*rest.get(1)? >> 6) & 3) as usize + 1;
+    rest = rest.get(1 + pkg_len_bytes + 1..)?;
+
+    let mut value = || -> Option<u16> {
+        let (value, used) = match *rest.first()? {
+            // BytePrefix
+            0x0A => (*rest.get(1)? as u16, 2),
+            // ZeroOp, OneOp
+            op @ (0x00 | 0x01) => (op as u16, 1),
+            _ => return None,
+        };
+        rest = &rest[used..];
+        Some(value)
+    };
+    let slp_typ_a = value()?;
+    let slp_typ_b = value()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+fn parse_fadt(fadt: u64, len: u64) -> Fadt {
+    // Old revisions of the table are shorter; fields past its end read as zero.
+    let has = |end: u64| end <= len;
+
+    let mut dsdt = read::<u32>(fadt + 40) as u64;
+    if has(148) {
+        let x_dsdt: u64 = read(fadt + 140);
+        if x_dsdt != 0 {
+            dsdt = x_dsdt;
+        }
+    }
+
+    let flags: u32 = if has(116) { read(fadt + 112) } else { 0 };
+    let reset_register = (has(129) && flags & FADT_RESET_REG_SUP != 0)
+        .then(|| read_gas(fadt + 116))
+        .filter(|reg| reg.address != 0);
+
+    Fadt {
+        sci_interrupt: read(fadt + 46),
+        smi_command_port: read(fadt + 48),
+        acpi_enable: read(fadt + 52),
+        acpi_disable: read(fadt + 53),
+        pm1a_event_block: read(fadt + 56),
+        pm1b_event_block: read(fadt + 60),
+        pm1a_control_block: read(fadt + 64),
+        pm1b_control_block: read(fadt + 68),
+        pm_timer_block: read(fadt + 76),
+        century: if has(109) { read(fadt + 108) } else { 0 },
+        boot_arch_flags: if has(111) { read(fadt + 109) } else { 0 },
+        flags,
+        reset_register,
+        reset_value: if has(129) { read(fadt + 128) } else { 0 },
+        dsdt,
+        s5_sleep_types: find_s5_sleep_types(dsdt),
+    }
+}
+
+fn parse_hpet(hpet: u64) -> Hpet {
+    Hpet {
+        event_timer_block_id: read(hpet + 36),
+        address: read_gas(hpet + 40),
+        hpet_number: read(hpet + 52),
+        min_tick: read(hpet + 53),
+    }
+}
+
+// Parse the tables reachable from the RSDP the bootloader found. Needs the heap.
+pub fn init(rsdp: u64) {
+    let Some((revision, entries)) = root_table_entries(rsdp) else {
+        klog!(Warn, "ACPI: no valid RSDP at {:#x}", rsdp);
+        return;
+    };
+
+    let mut info = AcpiInfo {
+        revision,
+        local_apic_address: 0,
+        madt_flags: 0,
+        cpus: Vec::new(),
+        io_apics: Vec::new(),
+        interrupt_overrides: Vec::new(),
+        local_apic_nmis: Vec::new(),
+        fadt: None,
+        hpet: None,
+    };
+
+    for phys in entries {
+        let Some((signature, len)) = table(phys) else {
+            continue;
+        };
+        match &signature {
+            b"APIC" => parse_madt(&mut info, phys, len),
+            b"FACP" => info.fadt = Some(parse_fadt(phys, len)),
+            b"HPET" => info.hpet = Some(parse_hpet(phys)),
+            _ => {}
+        }
+    }
+
+    klog!(
+        Debug,
+        "ACPI revision {}: {} CPU(s), {} I/O APIC(s), {} override(s), FADT: {}, HPET: {}",
+        info.revision,
+        info.enabled_cpus().count(),
+        info.io_apics.len(),
+        info.interrupt_overrides.len(),
+        info.fadt.is_some(),
+        info.hpet.is_some()
+    );
+
+    let _ = ACPI.set(info);
+}
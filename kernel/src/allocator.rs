@@ -1,44 +1,274 @@
+use crate::klog;
+use crate::sync::SpinLock;
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::UnsafeCell;
 use core::ptr::null_mut;
 
+// Small allocations are served from per-size-class free lists that are refilled one slab at a
+// time; everything bigger goes to an address-ordered free list of regions that coalesces on free.
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_SIZE: usize = 4096;
+const MIN_REGION: usize = core::mem::size_of::<FreeRegion>();
+// Smallest amount of memory requested from the grow callback at a time.
+const HEAP_GROW_STEP: usize = 256 * 1024;
+
+// Maps `size` more bytes at `start` (the current end of the heap); false if that failed.
+pub type GrowFn = fn(start: usize, size: usize) -> bool;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    // Bytes currently backed by memory.
+    pub size: usize,
+    // Bytes handed out and not yet freed, including size-class rounding.
+    pub used: usize,
+    pub peak: usize,
+}
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct FreeRegion {
+    size: usize,
+    next: *mut FreeRegion,
+}
+
+struct Heap {
+    classes: [*mut FreeBlock; SIZE_CLASSES.len()],
+    regions: *mut FreeRegion,
+    start: usize,
+    end: usize,
+    limit: usize,
+    grow: Option<GrowFn>,
+    used: usize,
+    peak: usize,
+}
+
+// The free lists only point into heap memory, which belongs to whoever holds the lock.
+unsafe impl Send for Heap {}
+
 pub struct HeapAllocator {
-    #[allow(dead_code)]
-    heap_start: usize,
-    heap_end: usize,
-    current: UnsafeCell<usize>,
+    heap: SpinLock<Heap>,
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
 }
 
-// TODO: AAAAAAAAAAAAAAAAAAAAA
-unsafe impl Sync for HeapAllocator {}
+// Every region boundary stays 16-byte aligned, so a region can always hold a `FreeRegion`.
+fn region_size(layout: &Layout) -> usize {
+    align_up(layout.size().max(MIN_REGION), MIN_REGION)
+}
 
 impl HeapAllocator {
-    pub const fn new(heap_start: usize, heap_size: u64) -> Self {
-        let heap_end = heap_start + heap_size as usize;
+    pub const fn empty() -> Self {
         HeapAllocator {
-            heap_start,
-            heap_end,
-            current: UnsafeCell::new(heap_start),
+            heap: SpinLock::new(Heap {
+                classes: [null_mut(); SIZE_CLASSES.len()],
+                regions: null_mut(),
+                start: 0,
+                end: 0,
+                limit: 0,
+                grow: None,
+                used: 0,
+                peak: 0,
+            }),
+        }
+    }
+
+    // Hand the memory in [heap_start, heap_start + heap_size) to the allocator. The range must
+    // be mapped and not used for anything else. When it runs dry the heap is extended through
+    // `grow`, up to `max_size` bytes in total.
+    pub unsafe fn init(&self, heap_start: usize, heap_size: u64, max_size: u64, grow: GrowFn) {
+        let mut heap = self.heap.lock();
+        let start = align_up(heap_start, SLAB_SIZE);
+        let end = (heap_start + heap_size as usize) & !(SLAB_SIZE - 1);
+        heap.start = start;
+        heap.end = start;
+        heap.limit = heap_start + max_size as usize;
+        heap.grow = Some(grow);
+        if end > start {
+            heap.free_region(start, end - start);
+            heap.end = end;
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let heap = self.heap.lock();
+        HeapStats {
+            size: heap.end - heap.start,
+            used: heap.used,
+            peak: heap.peak,
         }
     }
 }
 
-unsafe impl GlobalAlloc for HeapAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let align = layout.align();
-        let size = layout.size();
+impl Heap {
+    // Extend the heap by at least `min_bytes`. The new memory is mapped by the grow callback
+    // right after the current end, so it merges with a free region ending there.
+    unsafe fn grow(&mut self, min_bytes: usize) -> bool {
+        let grow = match self.grow {
+            Some(grow) => grow,
+            None => return false,
+        };
 
-        let aligned_current = self.current.get().align_offset(align);
+        let step = align_up(min_bytes.max(HEAP_GROW_STEP), SLAB_SIZE).min(self.limit - self.end);
+        if step < min_bytes || !grow(self.end, step) {
+            klog!(
+                Error,
+                "Kernel heap exhausted at {} KiB",
+                (self.end - self.start) / 1024
+            );
+            return false;
+        }
+
+        self.free_region(self.end, step);
+        self.end += step;
+        klog!(
+            Debug,
+            "Kernel heap grew to {} KiB",
+            (self.end - self.start) / 1024
+        );
+        true
+    }
 
-        let new_ptr = *self.current.get() + aligned_current;
+    // Insert [addr, addr + size) into the region list, merging with its neighbours.
+    unsafe fn free_region(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeRegion = null_mut();
+        let mut cur = self.regions;
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = (*cur).next;
+        }
+
+        let node = addr as *mut FreeRegion;
+        (*node).size = size;
+        (*node).next = cur;
+
+        if !cur.is_null() && addr + size == cur as usize {
+            (*node).size += (*cur).size;
+            (*node).next = (*cur).next;
+        }
 
-        if new_ptr + size > self.heap_end {
-            null_mut()
+        if prev.is_null() {
+            self.regions = node;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
         } else {
-            *self.current.get() = new_ptr + size;
-            new_ptr as *mut u8
+            (*prev).next = node;
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    unsafe fn alloc_region(&mut self, size: usize, align: usize) -> *mut u8 {
+        let align = align.max(MIN_REGION);
+
+        let mut prev: *mut FreeRegion = null_mut();
+        let mut cur = self.regions;
+        while !cur.is_null() {
+            let start = cur as usize;
+            let end = start + (*cur).size;
+            let next = (*cur).next;
+
+            let mut aligned = align_up(start, align);
+            if aligned != start && aligned - start < MIN_REGION {
+                aligned = align_up(start + MIN_REGION, align);
+            }
+
+            if aligned + size <= end {
+                if prev.is_null() {
+                    self.regions = next;
+                } else {
+                    (*prev).next = next;
+                }
+
+                if aligned > start {
+                    self.free_region(start, aligned - start);
+                }
+                if aligned + size < end {
+                    self.free_region(aligned + size, end - (aligned + size));
+                }
+
+                return aligned as *mut u8;
+            }
+
+            prev = cur;
+            cur = next;
+        }
+
+        null_mut()
+    }
+
+    unsafe fn alloc_layout(&mut self, layout: &Layout) -> *mut u8 {
+        match size_class(layout) {
+            Some(class) => self.alloc_small(class),
+            None => self.alloc_region(region_size(layout), layout.align()),
+        }
+    }
+
+    unsafe fn alloc_small(&mut self, class: usize) -> *mut u8 {
+        if self.classes[class].is_null() {
+            let slab = self.alloc_region(SLAB_SIZE, SLAB_SIZE);
+            if slab.is_null() {
+                return null_mut();
+            }
+
+            let block_size = SIZE_CLASSES[class];
+            for offset in (0..SLAB_SIZE).step_by(block_size).rev() {
+                let block = slab.add(offset) as *mut FreeBlock;
+                (*block).next = self.classes[class];
+                self.classes[class] = block;
+            }
+        }
+
+        let block = self.classes[class];
+        self.classes[class] = (*block).next;
+        block as *mut u8
+    }
+
+    unsafe fn free_small(&mut self, ptr: *mut u8, class: usize) {
+        let block = ptr as *mut FreeBlock;
+        (*block).next = self.classes[class];
+        self.classes[class] = block;
+    }
+}
+
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The grow callback maps pages with the heap locked, so it must not allocate itself.
+        let mut heap = self.heap.lock();
+        let (size, min_grow) = match size_class(&layout) {
+            Some(class) => (SIZE_CLASSES[class], 2 * SLAB_SIZE),
+            None => (region_size(&layout), region_size(&layout) + layout.align()),
+        };
+
+        let mut ptr = heap.alloc_layout(&layout);
+        if ptr.is_null() && heap.grow(min_grow) {
+            ptr = heap.alloc_layout(&layout);
+        }
+
+        if !ptr.is_null() {
+            heap.used += size;
+            heap.peak = heap.peak.max(heap.used);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heap = self.heap.lock();
+        match size_class(&layout) {
+            Some(class) => {
+                heap.free_small(ptr, class);
+                heap.used -= SIZE_CLASSES[class];
+            }
+            None => {
+                heap.free_region(ptr as usize, region_size(&layout));
+                heap.used -= region_size(&layout);
+            }
+        }
+    }
 }
@@ -0,0 +1,301 @@
+use crate::errno::{Errno, KResult};
+use crate::memory::{self, PHYSICAL_MEMORY_OFFSET};
+use crate::task::Task;
+use crate::uaccess::USER_SPACE_END;
+use crate::vma::{prot_page_flags, Vma, VmaKind, PROT_EXEC, PROT_READ, PROT_WRITE};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64Header {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Elf64ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+// What the caller needs to know about a loaded image to start it.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfImage {
+    pub entry: u64,
+    pub phdr_addr: u64,
+    pub phnum: u64,
+    pub phentsize: u64,
+    // First byte past the highest loaded segment.
+    pub end: u64,
+}
+
+pub fn parse_header(data: &[u8]) -> KResult<Elf64Header> {
+    if data.len() < core::mem::size_of::<Elf64Header>() {
+        return Err(Errno::ENOEXEC);
+    }
+
+    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Elf64Header) };
+
+    if header.e_ident[0..4] != ELF_MAGIC {
+        return Err(Errno::ENOEXEC);
+    }
+    if header.e_ident[4] != ELFCLASS64 || header.e_ident[5] != ELFDATA2LSB {
+        return Err(Errno::ENOEXEC);
+    }
+    if header.e_type != ET_EXEC {
+        return Err(Errno::ENOEXEC);
+    }
+    if header.e_machine != EM_X86_64 {
+        return Err(Errno::ENOEXEC);
+    }
+    if header.e_phentsize as usize != core::mem::size_of::<Elf64ProgramHeader>() {
+        return Err(Errno::ENOEXEC);
+    }
+
+    (header.e_phnum as u64)
+        .checked_mul(header.e_phentsize as u64)
+        .and_then(|size| size.checked_add(header.e_phoff))
+        .filter(|ph_end| *ph_end <= data.len() as u64)
+        .ok_or(Errno::ENOEXEC)?;
+
+    Ok(header)
+}
+
+pub fn program_header(data: &[u8], header: &Elf64Header, index: u16) -> Elf64ProgramHeader {
+    let offset = header.e_phoff as usize + index as usize * header.e_phentsize as usize;
+    unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset) as *const Elf64ProgramHeader) }
+}
+
+fn segment_prot(p_flags: u32) -> u32 {
+    let mut prot = 0;
+    if p_flags & PF_R != 0 {
+        prot |= PROT_READ;
+    }
+    if p_flags & PF_W != 0 {
+        prot |= PROT_WRITE;
+    }
+    if p_flags & PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+// Check that a PT_LOAD segment lies within the file and below the stack area, and return the
+// first address past it.
+fn check_segment(data: &[u8], ph: &Elf64ProgramHeader) -> KResult<u64> {
+    if ph.p_filesz > ph.p_memsz {
+        return Err(Errno::ENOEXEC);
+    }
+    ph.p_offset
+        .checked_add(ph.p_filesz)
+        .filter(|end| *end <= data.len() as u64)
+        .ok_or(Errno::ENOEXEC)?;
+    ph.p_vaddr
+        .checked_add(ph.p_memsz)
+        .filter(|end| *end <= USER_SPACE_END)
+        .filter(|end| *end <= memory::USER_STACK_TOP - memory::USER_STACK_MAX_SIZE)
+        .ok_or(Errno::ENOEXEC)
+}
+
+// Check everything about `data` that `load_elf` could reject, so that exec can fail before the
+// old image is gone.
+pub fn check_image(data: &[u8]) -> KResult<()> {
+    let header = parse_header(data)?;
+    let mut loadable = false;
+    for i in 0..header.e_phnum {
+        let ph = program_header(data, &header, i);
+        if ph.p_type == PT_LOAD && ph.p_memsz != 0 {
+            check_segment(data, &ph)?;
+            loadable = true;
+        }
+    }
+    if !loadable {
+        return Err(Errno::ENOEXEC);
+    }
+    Ok(())
+}
+
+// Map every PT_LOAD segment of `data` into the task's address space and record a VMA for it.
+// Pages holding file data are filled in right away; the copy goes through the physical memory
+// mapping, so the task's page table does not have to be active. Pure .bss pages are left to
+// demand paging.
+pub fn load_elf(
+    task: &mut Task,
+    data: &[u8],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> KResult<ElfImage> {
+    let header = parse_header(data)?;
+
+    let mut image = ElfImage {
+        entry: header.e_entry,
+        phdr_addr: 0,
+        phnum: header.e_phnum as u64,
+        phentsize: header.e_phentsize as u64,
+        end: 0,
+    };
+
+    for i in 0..header.e_phnum {
+        let ph = program_header(data, &header, i);
+
+        if ph.p_type == PT_PHDR {
+            image.phdr_addr = ph.p_vaddr;
+        }
+
+        if ph.p_type != PT_LOAD || ph.p_memsz == 0 {
+            continue;
+        }
+
+        let seg_end = check_segment(data, &ph)?;
+
+        // Without PT_PHDR the headers are still visible if the first segment covers them.
+        if image.phdr_addr == 0
+            && ph.p_offset <= header.e_phoff
+            && header.e_phoff < ph.p_offset + ph.p_filesz
+        {
+            image.phdr_addr = ph.p_vaddr + (header.e_phoff - ph.p_offset);
+        }
+
+        // A segment sharing its first page with the previous one starts its VMA after it; the
+        // shared page itself is mapped up front with merged permissions.
+        let vma_start = ph.p_vaddr & !(PAGE_SIZE - 1);
+        let vma_start = task.vmas.find(vma_start).map_or(vma_start, |vma| vma.end);
+        let vma_end = (seg_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if vma_start < vma_end {
+            task.vmas
+                .insert(Vma {
+                    start: vma_start,
+                    end: vma_end,
+                    prot: segment_prot(ph.p_flags),
+                    kind: VmaKind::Image,
+                    shared: false,
+                    file: None,
+                    file_offset: 0,
+                })
+                .map_err(|_| Errno::ENOEXEC)?;
+        }
+
+        map_segment(task, data, &ph, frame_allocator)?;
+
+        image.end = image.end.max(seg_end);
+    }
+
+    if image.end == 0 {
+        return Err(Errno::ENOEXEC);
+    }
+
+    Ok(image)
+}
+
+fn map_segment(
+    task: &mut Task,
+    data: &[u8],
+    ph: &Elf64ProgramHeader,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> KResult<()> {
+    if ph.p_filesz == 0 {
+        return Ok(());
+    }
+
+    let flags = prot_page_flags(segment_prot(ph.p_flags));
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(ph.p_vaddr));
+    let end_page =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(ph.p_vaddr + ph.p_filesz - 1));
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        // Neighbouring segments may share a page; reuse the frame and merge permissions.
+        let frame = match task.page_table.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame,
+                flags: old_flags,
+                ..
+            } => {
+                let frame = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+                let mut merged = old_flags | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    task.page_table
+                        .update_flags(page, merged)
+                        .map_err(|_| Errno::ENOMEM)?
+                        .flush();
+                }
+                frame
+            }
+            _ => {
+                let frame = frame_allocator.allocate_frame().ok_or(Errno::ENOMEM)?;
+                unsafe {
+                    core::ptr::write_bytes(
+                        (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8,
+                        0,
+                        PAGE_SIZE as usize,
+                    );
+                    task.page_table
+                        .map_to(page, frame, flags, frame_allocator)
+                        .map_err(|_| Errno::ENOMEM)?
+                        .flush();
+                }
+                task.phys_pages.push(frame);
+                frame
+            }
+        };
+
+        // Copy the part of the file image that falls into this page; the rest stays zero (.bss).
+        let page_start = page.start_address().as_u64();
+        let copy_start = page_start.max(ph.p_vaddr);
+        let copy_end = (page_start + PAGE_SIZE).min(ph.p_vaddr + ph.p_filesz);
+        if copy_start < copy_end {
+            let file_offset = (ph.p_offset + (copy_start - ph.p_vaddr)) as usize;
+            let len = (copy_end - copy_start) as usize;
+            let dest =
+                PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64() + (copy_start - page_start);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data.as_ptr().add(file_offset),
+                    dest as *mut u8,
+                    len,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Error numbers as used by Linux on x86_64, so that they can be handed to user space unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+#[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENXIO = 6,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EMLINK = 31,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+}
+
+pub type KResult<T> = Result<T, Errno>;
+
+const ALL: [Errno; 33] = [
+    Errno::EPERM,
+    Errno::ENOENT,
+    Errno::ESRCH,
+    Errno::EINTR,
+    Errno::EIO,
+    Errno::ENXIO,
+    Errno::E2BIG,
+    Errno::ENOEXEC,
+    Errno::EBADF,
+    Errno::ECHILD,
+    Errno::EAGAIN,
+    Errno::ENOMEM,
+    Errno::EACCES,
+    Errno::EFAULT,
+    Errno::EBUSY,
+    Errno::EEXIST,
+    Errno::EXDEV,
+    Errno::ENODEV,
+    Errno::ENOTDIR,
+    Errno::EISDIR,
+    Errno::EINVAL,
+    Errno::ENFILE,
+    Errno::EMFILE,
+    Errno::EFBIG,
+    Errno::ENOSPC,
+    Errno::ESPIPE,
+    Errno::EROFS,
+    Errno::EMLINK,
+    Errno::ERANGE,
+    Errno::ENAMETOOLONG,
+    Errno::ENOSYS,
+    Errno::ENOTEMPTY,
+    Errno::ELOOP,
+];
+
+impl Errno {
+    // Filesystem hooks are `extern "C"` and report failure as a negative errno.
+    pub fn as_isize(self) -> isize {
+        -(self as isize)
+    }
+
+    // Inverse of `as_isize`. Unknown values (including the old bare -1) become EIO.
+    pub fn from_isize(value: isize) -> Errno {
+        let code = value.unsigned_abs();
+        ALL.iter()
+            .copied()
+            .find(|errno| *errno as usize == code)
+            .unwrap_or(Errno::EIO)
+    }
+
+    // Syscalls return -errno in rax, like Linux.
+    pub fn as_syscall_return(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+// Turn the return value of a filesystem hook into a Result.
+pub fn check(value: isize) -> KResult<usize> {
+    if value < 0 {
+        Err(Errno::from_isize(value))
+    } else {
+        Ok(value as usize)
+    }
+}
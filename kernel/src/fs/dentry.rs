@@ -10,4 +10,5 @@ pub struct Dentry {
     pub d_op: Option<&'static crate::fs::dentry_operations::DentryOperations>,
     pub d_parent: *mut Dentry, // Parent dentry (null for root)
     pub d_subdirs: BTreeMap<String, *mut Dentry>, // Child dentries
+    pub d_count: u32,          // Task working and root directories, and mounts, pointing here
 }
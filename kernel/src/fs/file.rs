@@ -1,8 +1,56 @@
 use crate::fs::inode::Inode;
+use crate::fs::vfs::{self, VfsGuard};
 use crate::types::FMode;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::ManuallyDrop;
 
 pub struct File {
     pub f_inode: *mut Inode,
     pub f_mode: FMode,
     pub f_pos: u64,
 }
+
+// An open file held by a descriptor table and by the areas mapping it, closed when the last
+// reference goes away. It is only used with the VFS lock held; `get` and `get_mut` borrow the
+// guard, so a `&mut File` can't coexist with any other reference to it.
+pub struct OpenFile {
+    file: UnsafeCell<ManuallyDrop<Box<File>>>,
+}
+
+unsafe impl Send for OpenFile {}
+unsafe impl Sync for OpenFile {}
+
+impl OpenFile {
+    pub fn new(file: Box<File>) -> Arc<Self> {
+        Arc::new(OpenFile {
+            file: UnsafeCell::new(ManuallyDrop::new(file)),
+        })
+    }
+
+    pub fn get<'a>(&'a self, _vfs: &'a VfsGuard) -> &'a File {
+        unsafe { &*self.file.get() }
+    }
+
+    pub fn get_mut<'a>(&'a self, _vfs: &'a mut VfsGuard) -> &'a mut File {
+        unsafe { &mut *self.file.get() }
+    }
+}
+
+// Closing takes the VFS lock, so the last reference must not go away with it, or the task
+// table, locked.
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        let _vfs = vfs::lock();
+        vfs::close_file(unsafe { ManuallyDrop::take(self.file.get_mut()) });
+    }
+}
+
+impl fmt::Debug for OpenFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The inode of an open file never changes.
+        write!(f, "OpenFile({:p})", unsafe { (*self.file.get()).f_inode })
+    }
+}
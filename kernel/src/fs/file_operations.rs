@@ -7,10 +7,13 @@ type ReadFn =
     unsafe extern "C" fn(file: *mut File, buf: *mut u8, count: usize, pos: *mut u64) -> isize;
 type WriteFn =
     unsafe extern "C" fn(file: *mut File, buf: *const u8, count: usize, pos: *mut u64) -> isize;
+// Store the physical address of the frame backing page `index` of the file in `phys`.
+pub type MmapPageFn = unsafe extern "C" fn(file: *mut File, index: u64, phys: *mut u64) -> isize;
 
 pub struct FileOperations {
     pub open: Option<OpenFn>,
     pub release: Option<ReleaseFn>,
     pub read: Option<ReadFn>,
     pub write: Option<WriteFn>,
+    pub mmap_page: Option<MmapPageFn>,
 }
@@ -2,16 +2,21 @@ use crate::fs::dentry::Dentry;
 use crate::fs::file_operations::FileOperations;
 use crate::fs::inode_operations::InodeOperations;
 use crate::fs::super_block::SuperBlock;
+use crate::time::{self, Timespec};
 use crate::types::{Gid, Mode, Uid};
 use alloc::collections::LinkedList;
 
 pub struct Inode {
     pub i_ino: u64,
-    pub i_count: u32,
+    pub i_count: u32, // Open files referring to this inode
+    pub i_nlink: u32, // Hard links, counting "." and ".." entries for directories
     pub i_mode: Mode,
     pub i_uid: Uid,
     pub i_gid: Gid,
     pub i_size: u64,           // File size in bytes
+    pub i_atime: Timespec,     // Last access
+    pub i_mtime: Timespec,     // Last change of the contents
+    pub i_ctime: Timespec,     // Last change of the contents or the inode itself
     pub i_sb: *mut SuperBlock, // Superblock this inode belongs to
     pub file_operations: Option<&'static FileOperations>,
     pub inode_operations: Option<&'static InodeOperations>,
@@ -20,3 +25,25 @@ pub struct Inode {
     // For RAMFS, this will point to file data storage
     pub i_private: *mut u8,
 }
+
+impl Inode {
+    pub fn touch_atime(&mut self) {
+        self.i_atime = time::now();
+    }
+
+    // The contents changed, which also counts as an inode change.
+    pub fn touch_mtime(&mut self) {
+        let now = time::now();
+        self.i_mtime = now;
+        self.i_ctime = now;
+    }
+
+    pub fn touch_ctime(&mut self) {
+        self.i_ctime = time::now();
+    }
+
+    pub fn remove_dentry(&mut self, dentry: *mut Dentry) {
+        let dentries = core::mem::take(&mut self.i_dentry);
+        self.i_dentry = dentries.into_iter().filter(|&d| d != dentry).collect();
+    }
+}
@@ -31,6 +31,9 @@ type SymlinkFn =
 
 type RmdirFn = unsafe extern "C" fn(dir: *mut Inode, dentry: *mut Dentry) -> isize;
 
+// Copy the target of a symbolic link into `buf`, returning its length.
+type ReadlinkFn = unsafe extern "C" fn(dentry: *mut Dentry, buf: *mut u8, buflen: usize) -> isize;
+
 type RenameFn = unsafe extern "C" fn(
     old_dir: *mut Inode,
     old_dentry: *mut Dentry,
@@ -47,4 +50,5 @@ pub struct InodeOperations {
     pub symlink: Option<SymlinkFn>,
     pub rmdir: Option<RmdirFn>,
     pub rename: Option<RenameFn>,
+    pub readlink: Option<ReadlinkFn>,
 }
@@ -1,10 +1,12 @@
-mod dentry;
+pub mod dentry;
 mod dentry_operations;
 pub mod file;
 mod file_operations;
-mod inode;
+pub mod inode;
 mod inode_operations;
+pub(crate) mod namei;
 pub(crate) mod ramfs;
+pub(crate) mod stat;
 mod statfs;
 mod super_block;
 mod super_operations;
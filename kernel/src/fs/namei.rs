@@ -0,0 +1,156 @@
+use crate::errno::{self, Errno, KResult};
+use crate::fs::dentry::Dentry;
+use crate::fs::vfs::{self, NAME_MAX, PATH_MAX};
+use crate::task;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+// Follow a symbolic link in the last component too; links in the middle of a path are always
+// followed.
+pub const LOOKUP_FOLLOW: u32 = 1 << 0;
+
+// Symbolic links followed in one lookup before giving up with ELOOP, as on Linux.
+const MAX_SYMLINK_FOLLOWS: u32 = 40;
+
+// Root and working directory of the calling task. Kernel tasks, and tasks that never changed
+// directory, use the global root for both.
+pub fn current_root_and_cwd() -> (*mut Dentry, *mut Dentry) {
+    let root = vfs::root_dentry();
+    match task::with_current_task(|task| (task.root, task.cwd)) {
+        Some((task_root, cwd)) => (
+            if task_root.is_null() { root } else { task_root },
+            if cwd.is_null() { root } else { cwd },
+        ),
+        None => (root, root),
+    }
+}
+
+// Resolve `path` for the calling task, relative to its working directory unless it is absolute.
+pub fn path_lookup(path: &str, flags: u32) -> KResult<*mut Dentry> {
+    let (root, cwd) = current_root_and_cwd();
+    path_lookup_at(root, cwd, path, flags)
+}
+
+// Resolve `path` starting from `start`; absolute paths and symbolic links to them start over at
+// `root`, and `..` never leaves it.
+pub fn path_lookup_at(
+    root: *mut Dentry,
+    start: *mut Dentry,
+    path: &str,
+    flags: u32,
+) -> KResult<*mut Dentry> {
+    let mut links = 0;
+    walk(root, start, path, flags, &mut links)
+}
+
+fn walk(
+    root: *mut Dentry,
+    start: *mut Dentry,
+    path: &str,
+    flags: u32,
+    links: &mut u32,
+) -> KResult<*mut Dentry> {
+    if root.is_null() || start.is_null() || path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    if path.len() >= PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+
+    let mut current = if path.starts_with('/') { root } else { start };
+    // "dir/" has to name a directory, so a link there is followed as well.
+    let must_be_dir = path.ends_with('/');
+    let mut components = path.split('/').filter(|s| !s.is_empty()).peekable();
+
+    while let Some(component) = components.next() {
+        let last = components.peek().is_none();
+        unsafe {
+            if !vfs::is_dir((*current).d_inode) {
+                return Err(Errno::ENOTDIR);
+            }
+        }
+        if component.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        match component {
+            "." => continue,
+            ".." => {
+                // From the root of a mounted filesystem, go up from the directory it covers.
+                while current != root && unsafe { (*current).d_parent.is_null() } {
+                    match vfs::mount_point_of(current) {
+                        Some(mountpoint) => current = mountpoint,
+                        None => break,
+                    }
+                }
+                // The root of the walk, and the root of the filesystem tree, are their own parent.
+                let parent = unsafe { (*current).d_parent };
+                if current != root && !parent.is_null() {
+                    current = parent;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let child = lookup_child(current, component)?;
+        let follow = !last || must_be_dir || flags & LOOKUP_FOLLOW != 0;
+        if follow && vfs::is_symlink(unsafe { (*child).d_inode }) {
+            *links += 1;
+            if *links > MAX_SYMLINK_FOLLOWS {
+                return Err(Errno::ELOOP);
+            }
+            // Relative targets are resolved from the directory holding the link.
+            let target = vfs::read_link(child)?;
+            current = walk(root, current, &target, LOOKUP_FOLLOW, links)?;
+        } else {
+            current = vfs::follow_mount(child);
+        }
+    }
+
+    if must_be_dir && !vfs::is_dir(unsafe { (*current).d_inode }) {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(current)
+}
+
+// Find `name` in `dir`, asking the filesystem when it is not in the dentry cache.
+fn lookup_child(dir: *mut Dentry, name: &str) -> KResult<*mut Dentry> {
+    unsafe {
+        let dir_ref = &mut *dir;
+        if let Some(child) = dir_ref.d_subdirs.get(name) {
+            return Ok(*child);
+        }
+
+        let lookup_fn = (*dir_ref.d_inode)
+            .inode_operations
+            .and_then(|ops| ops.lookup)
+            .ok_or(Errno::ENOENT)?;
+
+        let dentry = Box::into_raw(Box::new(Dentry {
+            d_name: String::from(name),
+            d_inode: core::ptr::null_mut(),
+            d_sb: dir_ref.d_sb,
+            d_op: dir_ref.d_op,
+            d_parent: dir,
+            d_subdirs: BTreeMap::new(),
+            d_count: 0,
+        }));
+
+        // The filesystem attaches the inode if the name exists.
+        let result = errno::check(lookup_fn(
+            dir_ref.d_inode,
+            dentry,
+            name.as_ptr(),
+            name.len(),
+        ));
+        if result.is_err() || (*dentry).d_inode.is_null() {
+            let _ = Box::from_raw(dentry);
+            return Err(result.err().unwrap_or(Errno::ENOENT));
+        }
+
+        dir_ref.d_subdirs.insert(String::from(name), dentry);
+        Ok(dentry)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::errno::KResult;
 use crate::fs::dentry::Dentry;
 use crate::fs::ramfs::ramfs_file_operations;
 use crate::fs::ramfs::ramfs_inode_operations;
@@ -10,7 +11,7 @@ use crate::types::{Dev, Gid, Mode, Uid};
 use alloc::boxed::Box;
 use alloc::string::String;
 
-fn ramfs_mount(fs: &mut Filesystem, dev: u32, mount_point: &str) -> *mut Dentry {
+fn ramfs_mount(fs: &mut Filesystem, dev: u32, mount_point: &str) -> KResult<*mut Dentry> {
     klog!(Debug, "Mounting ramfs with dev={}", dev);
     let fs_static: &'static Filesystem = unsafe { core::mem::transmute(fs) };
 
@@ -23,23 +24,14 @@ fn ramfs_mount(fs: &mut Filesystem, dev: u32, mount_point: &str) -> *mut Dentry
 
     let sb_ptr = Box::into_raw(sb);
 
-    let root_inode = Box::new(crate::fs::inode::Inode {
-        i_ino: 1,
-        i_count: 1,
-        i_mode: Mode::from(0o40777),
-        i_uid: Uid::from(0),
-        i_gid: Gid::from(0),
-        i_size: 0,
-        i_sb: sb_ptr,
-        file_operations: Some(&ramfs_file_operations::RAMFS_FILE_OPERATIONS),
-        inode_operations: Some(&ramfs_inode_operations::RAMFS_INODE_OPERATIONS),
-        i_dentry: alloc::collections::LinkedList::new(),
-        i_private: core::ptr::null_mut(),
-    });
-    let root_inode_ptr = Box::into_raw(root_inode);
-
+    // Every instance's root comes from the shared inode number space, like any other inode.
+    let root_inode_ptr =
+        vfs::allocate_empty_inode(Mode::from(0o40777), Uid::from(0), Gid::from(0), sb_ptr);
     unsafe {
-        crate::fs::vfs::INODES_LIST.insert(1, root_inode_ptr);
+        let root_inode = &mut *root_inode_ptr;
+        root_inode.i_nlink = 2;
+        root_inode.file_operations = Some(&ramfs_file_operations::RAMFS_FILE_OPERATIONS);
+        root_inode.inode_operations = Some(&ramfs_inode_operations::RAMFS_INODE_OPERATIONS);
     }
 
     let root_dentry = Box::new(Dentry {
@@ -49,6 +41,7 @@ fn ramfs_mount(fs: &mut Filesystem, dev: u32, mount_point: &str) -> *mut Dentry
         d_op: None,
         d_parent: core::ptr::null_mut(),
         d_subdirs: alloc::collections::BTreeMap::new(),
+        d_count: 0,
     });
     let root_dentry_ptr = Box::into_raw(root_dentry);
 
@@ -56,7 +49,7 @@ fn ramfs_mount(fs: &mut Filesystem, dev: u32, mount_point: &str) -> *mut Dentry
         (*sb_ptr).s_root = root_dentry_ptr;
         (*root_inode_ptr).i_dentry.push_back(root_dentry_ptr);
     }
-    root_dentry_ptr
+    Ok(root_dentry_ptr)
 }
 
 fn ramfs_kill_sb(sb: &mut SuperBlock) -> i32 {
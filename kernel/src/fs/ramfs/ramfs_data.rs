@@ -1,41 +1,147 @@
+use crate::errno::{Errno, KResult};
+use crate::memory;
+use crate::memory::PHYSICAL_MEMORY_OFFSET;
+use crate::sync::Mutex;
 use alloc::collections::BTreeMap;
-use alloc::vec;
 use alloc::vec::Vec;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
 
-static mut RAMFS_DATA: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+const PAGE_SIZE: usize = 4096;
 
-#[inline(always)]
-unsafe fn data_map() -> &'static mut BTreeMap<u64, Vec<u8>> {
-    &mut RAMFS_DATA
+// File contents are kept in whole physical frames so mmap can map them straight into user space.
+// The file holds one reference to each frame; mappings take their own with memory::share_frame.
+pub struct RamfsData {
+    pages: Vec<PhysFrame<Size4KiB>>,
 }
 
-pub unsafe fn ramfs_get_data(ino: u64) -> Option<&'static mut Vec<u8>> {
-    data_map().get_mut(&ino).map(|v| core::mem::transmute(v))
+fn page_ptr(frame: PhysFrame) -> *mut u8 {
+    (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8
 }
 
-pub unsafe fn ramfs_set_data(ino: u64, data: Vec<u8>) {
-    data_map().insert(ino, data);
+impl RamfsData {
+    const fn new() -> Self {
+        RamfsData { pages: Vec::new() }
+    }
+
+    // Make sure the first `count` pages exist; new ones are zero-filled.
+    fn ensure_pages(&mut self, count: usize) -> KResult<()> {
+        let mut frame_allocator = memory::frame_allocator();
+        while self.pages.len() < count {
+            let frame = frame_allocator.allocate_frame().ok_or(Errno::ENOSPC)?;
+            unsafe {
+                core::ptr::write_bytes(page_ptr(frame), 0, PAGE_SIZE);
+            }
+            self.pages.push(frame);
+        }
+        Ok(())
+    }
+
+    // Copy out `buf.len()` bytes at `offset`. The caller bounds the range by the file size;
+    // pages that were never written read as zeros.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let in_page = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - in_page).min(buf.len() - done);
+            match self.pages.get(pos / PAGE_SIZE) {
+                Some(frame) => unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        page_ptr(*frame).add(in_page),
+                        buf.as_mut_ptr().add(done),
+                        len,
+                    );
+                },
+                None => buf[done..done + len].fill(0),
+            }
+            done += len;
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> KResult<()> {
+        self.ensure_pages((offset + buf.len()).div_ceil(PAGE_SIZE))?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let in_page = pos % PAGE_SIZE;
+            let len = (PAGE_SIZE - in_page).min(buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buf.as_ptr().add(done),
+                    page_ptr(self.pages[pos / PAGE_SIZE]).add(in_page),
+                    len,
+                );
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    // Frame backing page `index`, allocated if the file does not reach that far yet.
+    pub fn page(&mut self, index: usize) -> KResult<PhysFrame> {
+        self.ensure_pages(index + 1)?;
+        Ok(self.pages[index])
+    }
+
+    // Drop the pages past `size` and zero the tail of the last one, so that growing the file
+    // again reads zeros.
+    pub fn truncate(&mut self, size: usize) {
+        let keep = size.div_ceil(PAGE_SIZE).min(self.pages.len());
+        for frame in self.pages.drain(keep..) {
+            memory::release_frame(frame, &mut memory::frame_allocator());
+        }
+        if !size.is_multiple_of(PAGE_SIZE) {
+            if let Some(frame) = self.pages.get(size / PAGE_SIZE) {
+                unsafe {
+                    core::ptr::write_bytes(
+                        page_ptr(*frame).add(size % PAGE_SIZE),
+                        0,
+                        PAGE_SIZE - size % PAGE_SIZE,
+                    );
+                }
+            }
+        }
+    }
 }
 
-pub unsafe fn ramfs_allocate_data(ino: u64) -> &'static mut Vec<u8> {
-    let map = data_map();
-    let entry = map.entry(ino).or_insert_with(Vec::new);
-    core::mem::transmute(entry)
+impl Drop for RamfsData {
+    fn drop(&mut self) {
+        self.truncate(0);
+    }
 }
 
-pub unsafe fn ramfs_remove_data(ino: u64) {
-    data_map().remove(&ino);
+// File contents by inode number. Reads and writes copy whole buffers under the lock, so it is a
+// sleeping mutex rather than a spinlock.
+static RAMFS_DATA: Mutex<BTreeMap<u64, RamfsData>> = Mutex::new(BTreeMap::new());
+
+// Run `f` on the data of `ino`, if the inode has any.
+pub fn ramfs_with_data<R>(ino: u64, f: impl FnOnce(&mut RamfsData) -> R) -> Option<R> {
+    RAMFS_DATA.lock().get_mut(&ino).map(f)
 }
 
-pub unsafe fn ramfs_try_remove_data(ino: u64) -> bool {
-    data_map().remove(&ino).is_some()
+// Like `ramfs_with_data`, creating empty data for the inode first if needed.
+pub fn ramfs_with_allocated_data<R>(ino: u64, f: impl FnOnce(&mut RamfsData) -> R) -> R {
+    f(RAMFS_DATA.lock().entry(ino).or_insert_with(RamfsData::new))
 }
 
-pub unsafe fn ramfs_resize_data(ino: u64, new_size: usize) {
-    let map = data_map();
-    if let Some(data) = map.get_mut(&ino) {
-        data.resize(new_size, 0);
-    } else {
-        map.insert(ino, vec![0; new_size]);
-    }
+pub fn ramfs_allocate_data(ino: u64) {
+    ramfs_with_allocated_data(ino, |_| ());
+}
+
+pub fn ramfs_remove_data(ino: u64) {
+    ramfs_try_remove_data(ino);
+}
+
+pub fn ramfs_try_remove_data(ino: u64) -> bool {
+    // Dropping the data frees its frames; do that after the lock is released.
+    let data = RAMFS_DATA.lock().remove(&ino);
+    data.is_some()
+}
+
+pub fn ramfs_resize_data(ino: u64, new_size: usize) -> KResult<()> {
+    ramfs_with_allocated_data(ino, |data| {
+        data.truncate(new_size);
+        data.ensure_pages(new_size.div_ceil(PAGE_SIZE))
+    })
 }
@@ -1,7 +1,9 @@
+use crate::errno::Errno;
 use crate::fs::file::File;
 use crate::fs::file_operations::FileOperations;
 use crate::fs::inode::Inode;
 use crate::fs::ramfs::ramfs_data;
+use crate::fs::vfs;
 
 unsafe extern "C" fn ramfs_read(
     file: *mut File,
@@ -10,16 +12,20 @@ unsafe extern "C" fn ramfs_read(
     pos: *mut u64,
 ) -> isize {
     if file.is_null() || buf.is_null() || pos.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
     let file_ref = &*file;
     let inode = file_ref.f_inode;
     if inode.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
-    let inode_ref = &*inode;
+    if vfs::is_dir(inode) {
+        return Errno::EISDIR.as_isize();
+    }
+
+    let inode_ref = &mut *inode;
     let current_pos = *pos;
     let file_size = inode_ref.i_size as usize;
 
@@ -28,12 +34,6 @@ unsafe extern "C" fn ramfs_read(
         return 0; // EOF
     }
 
-    // Get the file data
-    let data = ramfs_data::ramfs_get_data(inode_ref.i_ino);
-    let data_vec = match data {
-        Some(vec) => vec,
-        None => return 0, // No data available
-    };
     let available = file_size - (current_pos as usize);
     let to_read = if count > available { available } else { count };
 
@@ -41,13 +41,17 @@ unsafe extern "C" fn ramfs_read(
         return 0;
     }
 
-    // Copy data to user buffer
-    let start = current_pos as usize;
-    let end = start + to_read;
-    let src = &data_vec[start..end];
-    unsafe {
-        core::ptr::copy_nonoverlapping(src.as_ptr(), buf, to_read);
+    // Copy data to the caller's buffer
+    let read = ramfs_data::ramfs_with_data(inode_ref.i_ino, |data| {
+        data.read(
+            current_pos as usize,
+            core::slice::from_raw_parts_mut(buf, to_read),
+        )
+    });
+    if read.is_none() {
+        return 0; // No data available
     }
+    inode_ref.touch_atime();
 
     // Update position
     *pos = (current_pos as usize + to_read) as u64;
@@ -62,31 +66,31 @@ unsafe extern "C" fn ramfs_write(
     pos: *mut u64,
 ) -> isize {
     if file.is_null() || buf.is_null() || pos.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
     let file_ref = &*file;
     let inode = file_ref.f_inode;
     if inode.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
+    }
+
+    if vfs::is_dir(inode) {
+        return Errno::EISDIR.as_isize();
     }
 
     let inode_ref = &mut *inode;
     let current_pos = *pos;
 
-    // Get or allocate file data
-    let data = ramfs_data::ramfs_allocate_data(inode_ref.i_ino);
-
-    // Ensure the data buffer is large enough
-    let required_size = (current_pos as usize) + count;
-    if data.len() < required_size {
-        data.resize(required_size, 0);
-    }
-
-    // Copy data from user buffer
-    let start = current_pos as usize;
-    unsafe {
-        core::ptr::copy_nonoverlapping(buf, data.as_mut_ptr().add(start), count);
+    // Copy data from the caller's buffer, allocating the data and its pages as needed
+    let written = ramfs_data::ramfs_with_allocated_data(inode_ref.i_ino, |data| {
+        data.write(
+            current_pos as usize,
+            core::slice::from_raw_parts(buf, count),
+        )
+    });
+    if let Err(err) = written {
+        return err.as_isize();
     }
 
     // Update file size if we wrote past the end
@@ -94,6 +98,7 @@ unsafe extern "C" fn ramfs_write(
     if new_size > inode_ref.i_size as usize {
         inode_ref.i_size = new_size as u64;
     }
+    inode_ref.touch_mtime();
 
     // Update position
     *pos = new_size as u64;
@@ -103,7 +108,7 @@ unsafe extern "C" fn ramfs_write(
 
 unsafe extern "C" fn ramfs_open(inode: *mut Inode, file: *mut File) -> isize {
     if inode.is_null() || file.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
     // For RAMFS, we just need to ensure data storage exists
@@ -113,9 +118,23 @@ unsafe extern "C" fn ramfs_open(inode: *mut Inode, file: *mut File) -> isize {
     0
 }
 
+unsafe extern "C" fn ramfs_mmap_page(file: *mut File, index: u64, phys: *mut u64) -> isize {
+    if file.is_null() || phys.is_null() || (*file).f_inode.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let ino = (*(*file).f_inode).i_ino;
+    match ramfs_data::ramfs_with_allocated_data(ino, |data| data.page(index as usize)) {
+        Ok(frame) => {
+            *phys = frame.start_address().as_u64();
+            0
+        }
+        Err(err) => err.as_isize(),
+    }
+}
+
 unsafe extern "C" fn ramfs_release(_inode: *mut Inode, _file: *mut File) -> isize {
-    // RAMFS data cleanup is handled in close_file when i_count reaches 0
-    // This function is called before the count is decremented, so we don't free here
+    // RAMFS data is freed with the inode, once it is neither linked nor open
     0
 }
 
@@ -124,4 +143,5 @@ pub static RAMFS_FILE_OPERATIONS: FileOperations = FileOperations {
     release: Some(ramfs_release),
     read: Some(ramfs_read),
     write: Some(ramfs_write),
+    mmap_page: Some(ramfs_mmap_page),
 };
@@ -1,16 +1,19 @@
+use crate::errno::Errno;
 use crate::fs::dentry::Dentry;
 use crate::fs::inode::Inode;
 use crate::fs::inode_operations::InodeOperations;
+use crate::fs::ramfs::ramfs_data;
 use crate::fs::ramfs::ramfs_file_operations;
 use crate::fs::vfs;
 use crate::types::{Gid, Mode, Uid};
+use core::ffi::{c_char, CStr};
 
 unsafe extern "C" fn ramfs_mkdir(dir: *mut Inode, dentry: *mut Dentry, mode: Mode) -> isize {
     if dir.is_null() || dentry.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
-    let dir_ref = &*dir;
+    let dir_ref = &mut *dir;
     let dentry_ref = &mut *dentry;
 
     // Ensure directory mode
@@ -18,7 +21,7 @@ unsafe extern "C" fn ramfs_mkdir(dir: *mut Inode, dentry: *mut Dentry, mode: Mod
 
     let new_inode = vfs::allocate_empty_inode(dir_mode, dir_ref.i_uid, dir_ref.i_gid, dir_ref.i_sb);
     if new_inode.is_null() {
-        return -1;
+        return Errno::ENOSPC.as_isize();
     }
 
     unsafe {
@@ -26,10 +29,15 @@ unsafe extern "C" fn ramfs_mkdir(dir: *mut Inode, dentry: *mut Dentry, mode: Mod
         new_inode_ref.inode_operations = dir_ref.inode_operations;
         new_inode_ref.file_operations = Some(&ramfs_file_operations::RAMFS_FILE_OPERATIONS);
         new_inode_ref.i_size = 0;
+        // Its entry in the parent and its own "."
+        new_inode_ref.i_nlink = 2;
         new_inode_ref.i_dentry.push_back(dentry);
     }
 
     dentry_ref.d_inode = new_inode;
+    // The new directory's ".." refers to the parent
+    dir_ref.i_nlink += 1;
+    dir_ref.touch_mtime();
 
     0
 }
@@ -42,16 +50,16 @@ unsafe extern "C" fn ramfs_create(
     gid: Gid,
 ) -> isize {
     if dir.is_null() || dentry.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
-    let dir_ref = &*dir;
+    let dir_ref = &mut *dir;
     let dentry_ref = &mut *dentry;
 
     // Allocate new inode
     let new_inode = vfs::allocate_empty_inode(mode, uid, gid, dir_ref.i_sb);
     if new_inode.is_null() {
-        return -1;
+        return Errno::ENOSPC.as_isize();
     }
 
     unsafe {
@@ -66,6 +74,7 @@ unsafe extern "C" fn ramfs_create(
 
     // Link dentry to inode
     dentry_ref.d_inode = new_inode;
+    dir_ref.touch_mtime();
 
     0
 }
@@ -77,7 +86,7 @@ unsafe extern "C" fn ramfs_lookup(
     namelen: usize,
 ) -> isize {
     if dir.is_null() || dentry.is_null() || name.is_null() {
-        return -1;
+        return Errno::EINVAL.as_isize();
     }
 
     // For ramfs, lookup is handled by the VFS layer through d_subdirs
@@ -86,13 +95,184 @@ unsafe extern "C" fn ramfs_lookup(
     0
 }
 
+// Detach `dentry` from its inode and drop the link. Directories have to be empty; everything in
+// ramfs is in the dentry cache, so its children are all there is.
+unsafe fn ramfs_remove_link(dir: *mut Inode, dentry: *mut Dentry) -> isize {
+    let dentry_ref = &mut *dentry;
+    let inode = dentry_ref.d_inode;
+    if inode.is_null() {
+        return Errno::ENOENT.as_isize();
+    }
+    if vfs::is_dir(inode) && !dentry_ref.d_subdirs.is_empty() {
+        return Errno::ENOTEMPTY.as_isize();
+    }
+
+    let inode_ref = &mut *inode;
+    inode_ref.remove_dentry(dentry);
+    inode_ref.touch_ctime();
+    dentry_ref.d_inode = core::ptr::null_mut();
+    (*dir).touch_mtime();
+
+    if vfs::is_dir(inode) {
+        // Its ".." no longer refers to the parent
+        (*dir).i_nlink -= 1;
+        vfs::drop_nlink(inode, inode_ref.i_nlink);
+    } else {
+        vfs::drop_nlink(inode, 1);
+    }
+    0
+}
+
+unsafe extern "C" fn ramfs_unlink(dir: *mut Inode, dentry: *mut Dentry) -> isize {
+    if dir.is_null() || dentry.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+    if vfs::is_dir((*dentry).d_inode) {
+        return Errno::EISDIR.as_isize();
+    }
+
+    ramfs_remove_link(dir, dentry)
+}
+
+unsafe extern "C" fn ramfs_rmdir(dir: *mut Inode, dentry: *mut Dentry) -> isize {
+    if dir.is_null() || dentry.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+    if !vfs::is_dir((*dentry).d_inode) {
+        return Errno::ENOTDIR.as_isize();
+    }
+
+    ramfs_remove_link(dir, dentry)
+}
+
+unsafe extern "C" fn ramfs_link(
+    old_dentry: *mut Dentry,
+    new_dir: *mut Inode,
+    new_dentry: *mut Dentry,
+) -> isize {
+    if old_dentry.is_null() || new_dir.is_null() || new_dentry.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let inode = (*old_dentry).d_inode;
+    if inode.is_null() {
+        return Errno::ENOENT.as_isize();
+    }
+    if vfs::is_dir(inode) {
+        return Errno::EPERM.as_isize();
+    }
+
+    let inode_ref = &mut *inode;
+    inode_ref.i_nlink += 1;
+    inode_ref.i_dentry.push_back(new_dentry);
+    inode_ref.touch_ctime();
+    (*new_dentry).d_inode = inode;
+    (*new_dir).touch_mtime();
+
+    0
+}
+
+unsafe extern "C" fn ramfs_symlink(
+    dir: *mut Inode,
+    dentry: *mut Dentry,
+    symname: *const u8,
+) -> isize {
+    if dir.is_null() || dentry.is_null() || symname.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let dir_ref = &mut *dir;
+    let target = CStr::from_ptr(symname as *const c_char).to_bytes();
+
+    // Symbolic link mode, always rwxrwxrwx
+    let mode = Mode::from(0o120777);
+    let new_inode = vfs::allocate_empty_inode(mode, dir_ref.i_uid, dir_ref.i_gid, dir_ref.i_sb);
+    if new_inode.is_null() {
+        return Errno::ENOSPC.as_isize();
+    }
+
+    // The target path is kept as the link's contents.
+    let new_inode_ref = &mut *new_inode;
+    let stored =
+        ramfs_data::ramfs_with_allocated_data(new_inode_ref.i_ino, |data| data.write(0, target));
+    if let Err(err) = stored {
+        vfs::drop_nlink(new_inode, 1);
+        return err.as_isize();
+    }
+    new_inode_ref.inode_operations = dir_ref.inode_operations;
+    new_inode_ref.i_size = target.len() as u64;
+    new_inode_ref.i_dentry.push_back(dentry);
+
+    (*dentry).d_inode = new_inode;
+    dir_ref.touch_mtime();
+
+    0
+}
+
+// The VFS has checked that the move is allowed and moves the dentry itself afterwards; this
+// replaces whatever `new_dentry` pointed at.
+unsafe extern "C" fn ramfs_rename(
+    old_dir: *mut Inode,
+    old_dentry: *mut Dentry,
+    new_dir: *mut Inode,
+    new_dentry: *mut Dentry,
+) -> isize {
+    if old_dir.is_null() || old_dentry.is_null() || new_dir.is_null() || new_dentry.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let inode = (*old_dentry).d_inode;
+    if inode.is_null() {
+        return Errno::ENOENT.as_isize();
+    }
+
+    if !(*new_dentry).d_inode.is_null() {
+        let result = ramfs_remove_link(new_dir, new_dentry);
+        if result != 0 {
+            return result;
+        }
+    }
+
+    // A directory's ".." moves to the new parent
+    if vfs::is_dir(inode) && old_dir != new_dir {
+        (*old_dir).i_nlink -= 1;
+        (*new_dir).i_nlink += 1;
+    }
+
+    (*inode).touch_ctime();
+    (*old_dir).touch_mtime();
+    (*new_dir).touch_mtime();
+
+    0
+}
+
+unsafe extern "C" fn ramfs_readlink(dentry: *mut Dentry, buf: *mut u8, buflen: usize) -> isize {
+    if dentry.is_null() || buf.is_null() {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let inode = (*dentry).d_inode;
+    if !vfs::is_symlink(inode) {
+        return Errno::EINVAL.as_isize();
+    }
+
+    let inode_ref = &mut *inode;
+    let len = (inode_ref.i_size as usize).min(buflen);
+    let buf = core::slice::from_raw_parts_mut(buf, len);
+    ramfs_data::ramfs_with_data(inode_ref.i_ino, |data| data.read(0, buf));
+    inode_ref.touch_atime();
+
+    len as isize
+}
+
 pub static RAMFS_INODE_OPERATIONS: InodeOperations = InodeOperations {
     create: Some(ramfs_create),
     lookup: Some(ramfs_lookup),
     mkdir: Some(ramfs_mkdir),
-    rmdir: None,
-    unlink: None,
-    link: None,
-    symlink: None,
-    rename: None,
+    rmdir: Some(ramfs_rmdir),
+    unlink: Some(ramfs_unlink),
+    link: Some(ramfs_link),
+    symlink: Some(ramfs_symlink),
+    rename: Some(ramfs_rename),
+    readlink: Some(ramfs_readlink),
 };
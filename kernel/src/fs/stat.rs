@@ -0,0 +1,128 @@
+use crate::fs::inode::Inode;
+use crate::time::Timespec;
+
+const BLOCK_SIZE: u64 = 4096;
+
+// `struct stat` as the x86_64 Linux syscalls lay it out.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub __pad0: u32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64, // In 512-byte units
+    pub st_atime: Timespec,
+    pub st_mtime: Timespec,
+    pub st_ctime: Timespec,
+    pub __unused: [i64; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatxTimestamp {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub __reserved: i32,
+}
+
+impl From<Timespec> for StatxTimestamp {
+    fn from(ts: Timespec) -> Self {
+        StatxTimestamp {
+            tv_sec: ts.tv_sec,
+            tv_nsec: ts.tv_nsec as u32,
+            __reserved: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Statx {
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    pub __spare0: u16,
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: StatxTimestamp,
+    pub stx_btime: StatxTimestamp,
+    pub stx_ctime: StatxTimestamp,
+    pub stx_mtime: StatxTimestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub stx_mnt_id: u64,
+    pub stx_dio_mem_align: u32,
+    pub stx_dio_offset_align: u32,
+    pub __spare3: [u64; 12],
+}
+
+// Everything up to and including the block count; there is no birth time.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+// Superblock device numbers are minors of the unnamed-device major 0.
+fn dev_minor(inode: &Inode) -> u32 {
+    if inode.i_sb.is_null() {
+        0
+    } else {
+        unsafe { (*inode.i_sb).s_dev.0 }
+    }
+}
+
+// Space taken up, counted in whole blocks as the data is allocated.
+fn blocks_512(inode: &Inode) -> u64 {
+    inode.i_size.div_ceil(BLOCK_SIZE) * (BLOCK_SIZE / 512)
+}
+
+pub fn stat(inode: &Inode) -> Stat {
+    let minor = dev_minor(inode) as u64;
+    Stat {
+        // Linux's `new_encode_dev` with major 0
+        st_dev: (minor & 0xff) | ((minor & !0xff) << 12),
+        st_ino: inode.i_ino,
+        st_nlink: inode.i_nlink as u64,
+        st_mode: inode.i_mode.0 as u32,
+        st_uid: inode.i_uid.0,
+        st_gid: inode.i_gid.0,
+        st_size: inode.i_size as i64,
+        st_blksize: BLOCK_SIZE as i64,
+        st_blocks: blocks_512(inode) as i64,
+        st_atime: inode.i_atime,
+        st_mtime: inode.i_mtime,
+        st_ctime: inode.i_ctime,
+        ..Default::default()
+    }
+}
+
+pub fn statx(inode: &Inode) -> Statx {
+    Statx {
+        stx_mask: STATX_BASIC_STATS,
+        stx_blksize: BLOCK_SIZE as u32,
+        stx_nlink: inode.i_nlink,
+        stx_uid: inode.i_uid.0,
+        stx_gid: inode.i_gid.0,
+        stx_mode: inode.i_mode.0,
+        stx_ino: inode.i_ino,
+        stx_size: inode.i_size,
+        stx_blocks: blocks_512(inode),
+        stx_atime: inode.i_atime.into(),
+        stx_ctime: inode.i_ctime.into(),
+        stx_mtime: inode.i_mtime.into(),
+        stx_dev_minor: dev_minor(inode),
+        ..Default::default()
+    }
+}
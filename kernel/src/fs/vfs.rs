@@ -1,62 +1,178 @@
+use crate::errno::{self, Errno, KResult};
 use crate::fs::dentry::Dentry;
 use crate::fs::file::File;
+use crate::fs::file_operations::MmapPageFn;
 use crate::fs::inode::Inode;
+use crate::fs::inode_operations::InodeOperations;
 use crate::fs::ramfs::ramfs;
 use crate::fs::super_block::SuperBlock;
+use crate::sync::{Mutex, MutexGuard, OnceCell, SpinLock};
+use crate::time;
 use crate::types::{FMode, Gid, Mode, Uid};
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, LinkedList};
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
 
-type MountFunc = fn(fs: &mut Filesystem, dev: u32, mount_point: &str) -> *mut Dentry;
+type MountFunc = fn(fs: &mut Filesystem, dev: u32, mount_point: &str) -> KResult<*mut Dentry>;
 type KillSbFunc = fn(sb: &mut SuperBlock) -> i32;
 
 pub struct Filesystem {
     pub name: &'static str,
     pub mount: Option<MountFunc>,
     pub kill_sb: Option<KillSbFunc>,
-    pub fs_supers: LinkedList<SuperBlock>,
+    pub fs_supers: LinkedList<*mut SuperBlock>, // Mounted instances
 }
 
-pub static mut FILESYSTEMS: LinkedList<Filesystem> = LinkedList::new();
+// Superblocks point into the dentry and inode trees, which are only touched by the VFS.
+unsafe impl Send for Filesystem {}
+
+pub static FILESYSTEMS: SpinLock<LinkedList<Filesystem>> = SpinLock::new(LinkedList::new());
 
 pub fn register_filesystem(fs: Filesystem) {
-    unsafe {
-        FILESYSTEMS.push_back(fs);
+    FILESYSTEMS.lock().push_back(fs);
+}
+
+// Run `f` on the filesystem type called `name` with the list locked.
+pub fn with_filesystem<R>(name: &str, f: impl FnOnce(&mut Filesystem) -> R) -> Option<R> {
+    let mut filesystems = FILESYSTEMS.lock();
+    filesystems.iter_mut().find(|fs| fs.name == name).map(f)
+}
+
+pub fn mount_filesystem(fs_name: &str, dev: u32, mount_point: &str) -> KResult<*mut Dentry> {
+    with_filesystem(fs_name, |fs| {
+        let mount_func = fs.mount.ok_or(Errno::ENODEV)?;
+        let root = mount_func(fs, dev, mount_point)?;
+        fs.fs_supers.push_back(unsafe { (*root).d_sb });
+        Ok(root)
+    })
+    .unwrap_or(Err(Errno::ENODEV))
+}
+
+// A filesystem root laid over a directory of another filesystem. The root filesystem is not in
+// the table; it isn't mounted on anything.
+pub struct Mount {
+    pub mountpoint: *mut Dentry,
+    pub root: *mut Dentry,
+}
+
+unsafe impl Send for Mount {}
+
+pub static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new(Vec::new());
+
+// Device numbers for filesystems without a backing device; 1 is the root filesystem.
+static NEXT_ANONYMOUS_DEV: AtomicU32 = AtomicU32::new(2);
+
+pub fn anonymous_dev() -> u32 {
+    NEXT_ANONYMOUS_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+// The root of whatever is mounted on `dentry`, topmost first if mounts are stacked, or `dentry`
+// itself.
+pub fn follow_mount(dentry: *mut Dentry) -> *mut Dentry {
+    let mounts = MOUNTS.lock();
+    let mut current = dentry;
+    // Later mounts on the same directory cover earlier ones.
+    while let Some(mount) = mounts
+        .iter()
+        .rev()
+        .find(|mount| mount.mountpoint == current)
+    {
+        current = mount.root;
     }
+    current
+}
+
+// The directory the filesystem rooted at `root` is mounted on.
+pub fn mount_point_of(root: *mut Dentry) -> Option<*mut Dentry> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| mount.root == root)
+        .map(|mount| mount.mountpoint)
 }
 
-pub fn get_filesystem_by_name(name: &str) -> Option<&'static mut Filesystem> {
+// Mount a new instance of `fs_name` on the directory `target`.
+pub fn mount(fs_name: &str, dev: u32, target: *mut Dentry) -> KResult<*mut Dentry> {
     unsafe {
-        for fs in FILESYSTEMS.iter_mut() {
-            if fs.name == name {
-                return Some(fs);
-            }
+        if target.is_null() {
+            return Err(Errno::ENOENT);
         }
+        if !is_dir((*target).d_inode) {
+            return Err(Errno::ENOTDIR);
+        }
+        // The root filesystem stays where it is.
+        if target == root_dentry() {
+            return Err(Errno::EBUSY);
+        }
+    }
+
+    let root = mount_filesystem(fs_name, dev, &get_full_path(target))?;
+    // The mountpoint can't be removed while something is mounted on it.
+    MOUNTS.lock().push(Mount {
+        mountpoint: dget(target),
+        root,
+    });
+    Ok(root)
+}
+
+// Whether anything in the tree below `dentry` is still in use: open files, working
+// directories, or filesystems mounted inside it.
+unsafe fn tree_busy(dentry: *mut Dentry) -> bool {
+    let dentry_ref = &*dentry;
+    if dentry_ref.d_count > 0 {
+        return true;
     }
-    None
+    if !dentry_ref.d_inode.is_null() && (*dentry_ref.d_inode).i_count > 0 {
+        return true;
+    }
+    dentry_ref.d_subdirs.values().any(|&child| tree_busy(child))
 }
 
-pub fn mount_filesystem(fs_name: &str, dev: u32, mount_point: &str) -> *mut Dentry {
-    if let Some(fs) = get_filesystem_by_name(fs_name) {
-        if let Some(mount_func) = fs.mount {
-            return mount_func(fs, dev, mount_point);
-        }
+// Unmount the filesystem whose root is `root`, i.e. what a path lookup of the mountpoint
+// returns. Fails with EBUSY while anything in it is in use.
+pub fn umount(root: *mut Dentry) -> KResult<()> {
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.root == root)
+        .ok_or(Errno::EINVAL)?;
+    if unsafe { tree_busy(root) } {
+        return Err(Errno::EBUSY);
     }
-    core::ptr::null_mut()
+
+    let mount = mounts.remove(index);
+    drop(mounts);
+    dput(mount.mountpoint);
+    unmount_filesystem(root)
 }
 
 pub fn get_full_path(dentry: *mut Dentry) -> String {
+    dentry_path(dentry, core::ptr::null_mut())
+}
+
+// Path of `dentry` as seen from `root`, e.g. a task's working directory relative to its root.
+pub fn dentry_path(dentry: *mut Dentry, root: *mut Dentry) -> String {
     let mut components = Vec::new();
     unsafe {
         let mut current = dentry;
-        while !current.is_null() {
+        while !current.is_null() && current != root {
             let dentry_ref = &*current;
-            // Skip root component (which is "/")
-            if dentry_ref.d_name != "/" {
-                components.push(dentry_ref.d_name.clone());
+            // Filesystem roots go by the name of the directory they are mounted on
+            if dentry_ref.d_parent.is_null() {
+                match mount_point_of(current) {
+                    Some(mountpoint) => {
+                        current = mountpoint;
+                        continue;
+                    }
+                    None => break,
+                }
             }
+            components.push(dentry_ref.d_name.clone());
             current = dentry_ref.d_parent;
         }
     }
@@ -77,80 +193,93 @@ pub fn get_full_path(dentry: *mut Dentry) -> String {
     path
 }
 
-pub static mut ROOT_DENTRY: *mut Dentry = core::ptr::null_mut();
+pub const NAME_MAX: usize = 255;
 
-pub fn vfs_init() {
-    ramfs::init_ramfs();
+// One lock for the whole VFS: the dentry trees and the counts, links and sizes of inodes. The
+// functions here and in `namei` expect it held, from resolving a path until the dentry it
+// returned is no longer used. Never hold it while touching user memory: a fault on a file
+// mapping takes it too.
+static VFS_LOCK: Mutex<()> = Mutex::new(());
 
-    unsafe {
-        ROOT_DENTRY = mount_filesystem("ramfs", 1, "/");
-    }
+pub struct VfsGuard {
+    _guard: MutexGuard<'static, ()>,
 }
 
-pub fn resolve_path(path: &str) -> *mut Dentry {
-    unsafe {
-        if ROOT_DENTRY.is_null() {
-            return core::ptr::null_mut();
-        }
+pub fn lock() -> VfsGuard {
+    VfsGuard {
+        _guard: VFS_LOCK.lock(),
+    }
+}
 
-        // Handle root path
-        if path == "/" || path.is_empty() {
-            return ROOT_DENTRY;
-        }
+// Root of the root filesystem, set by `vfs_init`. It is never unmounted.
+struct RootDentry(*mut Dentry);
 
-        let mut current_dentry = ROOT_DENTRY;
-        let components: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+unsafe impl Send for RootDentry {}
+unsafe impl Sync for RootDentry {}
 
-        for component in components {
-            let dentry_ref = &*current_dentry;
+static ROOT_DENTRY: OnceCell<RootDentry> = OnceCell::new();
 
-            // Check if inode exists and has lookup operation
-            if !dentry_ref.d_inode.is_null() {
-                let inode_ref = &*dentry_ref.d_inode;
-                if let Some(inode_ops) = inode_ref.inode_operations {
-                    // Try filesystem-specific lookup first
-                    if let Some(_lookup_fn) = inode_ops.lookup {
-                        // For now, we'll use the VFS lookup through d_subdirs
-                        // Filesystem-specific lookup can be enhanced later
-                    }
-                }
-            }
+// Null before `vfs_init` or if mounting the root filesystem failed.
+pub fn root_dentry() -> *mut Dentry {
+    ROOT_DENTRY
+        .get()
+        .map_or(core::ptr::null_mut(), |root| root.0)
+}
 
-            // VFS lookup through d_subdirs
-            if let Some(child_dentry) = dentry_ref.d_subdirs.get(component) {
-                current_dentry = *child_dentry;
-            } else {
-                return core::ptr::null_mut();
-            }
-        }
+pub fn vfs_init() {
+    ramfs::init_ramfs();
 
-        current_dentry
+    let _vfs = lock();
+    if let Ok(root) = mount_filesystem("ramfs", 1, "/") {
+        let _ = ROOT_DENTRY.set(RootDentry(root));
     }
 }
 
-pub fn mkdir(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: Gid) -> *mut Dentry {
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFLNK: u16 = 0o120000;
+
+pub fn is_dir(inode: *mut Inode) -> bool {
+    !inode.is_null() && unsafe { (*inode).i_mode.0 & S_IFMT == S_IFDIR }
+}
+
+pub fn is_symlink(inode: *mut Inode) -> bool {
+    !inode.is_null() && unsafe { (*inode).i_mode.0 & S_IFMT == S_IFLNK }
+}
+
+pub fn mkdir(
+    parent: *mut Dentry,
+    name: &str,
+    mode: Mode,
+    uid: Uid,
+    gid: Gid,
+) -> KResult<*mut Dentry> {
     unsafe {
         if parent.is_null() {
-            return core::ptr::null_mut();
+            return Err(Errno::ENOENT);
         }
 
         let parent_ref = &mut *parent;
 
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
         // Check if directory already exists
         if parent_ref.d_subdirs.contains_key(name) {
-            return core::ptr::null_mut();
+            return Err(Errno::EEXIST);
         }
 
-        // Check if parent has an inode
-        if parent_ref.d_inode.is_null() {
-            return core::ptr::null_mut();
+        // Check if parent is a directory
+        if !is_dir(parent_ref.d_inode) {
+            return Err(Errno::ENOTDIR);
         }
 
         let parent_inode = &*parent_ref.d_inode;
 
         // Check if parent inode has inode operations
         if parent_inode.inode_operations.is_none() {
-            return core::ptr::null_mut();
+            return Err(Errno::EPERM);
         }
 
         let inode_op = parent_inode.inode_operations.unwrap();
@@ -163,6 +292,7 @@ pub fn mkdir(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: Gid) ->
             d_op: parent_ref.d_op,
             d_parent: parent,
             d_subdirs: BTreeMap::new(),
+            d_count: 0,
         });
 
         let new_dentry_ptr = Box::into_raw(new_dentry);
@@ -170,24 +300,24 @@ pub fn mkdir(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: Gid) ->
         // Call filesystem-specific mkdir operation
         if let Some(mkdir_fn) = inode_op.mkdir {
             let result = mkdir_fn(parent_ref.d_inode, new_dentry_ptr, mode);
-            if result < 0 {
+            if let Err(err) = errno::check(result) {
                 // mkdir failed, clean up dentry
                 let _ = Box::from_raw(new_dentry_ptr);
-                return core::ptr::null_mut();
+                return Err(err);
             }
         } else {
             // No mkdir operation, use generic create
             if let Some(create) = inode_op.create {
                 let result = create(parent_ref.d_inode, new_dentry_ptr, mode, uid, gid);
-                if result < 0 {
+                if let Err(err) = errno::check(result) {
                     // create failed, clean up dentry
                     let _ = Box::from_raw(new_dentry_ptr);
-                    return core::ptr::null_mut();
+                    return Err(err);
                 }
             } else {
-                // No operations available, clean up and return null
+                // No operations available, clean up and fail
                 let _ = Box::from_raw(new_dentry_ptr);
-                return core::ptr::null_mut();
+                return Err(Errno::EPERM);
             }
         }
 
@@ -196,33 +326,43 @@ pub fn mkdir(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: Gid) ->
             .d_subdirs
             .insert(String::from(name), new_dentry_ptr);
 
-        new_dentry_ptr
+        Ok(new_dentry_ptr)
     }
 }
 
-pub fn create_file(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: Gid) -> *mut Dentry {
+pub fn create_file(
+    parent: *mut Dentry,
+    name: &str,
+    mode: Mode,
+    uid: Uid,
+    gid: Gid,
+) -> KResult<*mut Dentry> {
     unsafe {
         if parent.is_null() {
-            return core::ptr::null_mut();
+            return Err(Errno::ENOENT);
         }
 
         let parent_ref = &mut *parent;
 
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
         // Check if file already exists
         if parent_ref.d_subdirs.contains_key(name) {
-            return core::ptr::null_mut();
+            return Err(Errno::EEXIST);
         }
 
-        // Check if parent has an inode
-        if parent_ref.d_inode.is_null() {
-            return core::ptr::null_mut();
+        // Check if parent is a directory
+        if !is_dir(parent_ref.d_inode) {
+            return Err(Errno::ENOTDIR);
         }
 
         let parent_inode = &*parent_ref.d_inode;
 
         // Check if parent inode has inode operations
         if parent_inode.inode_operations.is_none() {
-            return core::ptr::null_mut();
+            return Err(Errno::EPERM);
         }
 
         let inode_op = parent_inode.inode_operations.unwrap();
@@ -235,6 +375,7 @@ pub fn create_file(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: G
             d_op: parent_ref.d_op,
             d_parent: parent,
             d_subdirs: BTreeMap::new(),
+            d_count: 0,
         });
 
         let new_dentry_ptr = Box::into_raw(new_dentry);
@@ -242,15 +383,15 @@ pub fn create_file(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: G
         // Call filesystem-specific create operation
         if let Some(create_fn) = inode_op.create {
             let result = create_fn(parent_ref.d_inode, new_dentry_ptr, mode, uid, gid);
-            if result < 0 {
+            if let Err(err) = errno::check(result) {
                 // create failed, clean up dentry
                 let _ = Box::from_raw(new_dentry_ptr);
-                return core::ptr::null_mut();
+                return Err(err);
             }
         } else {
-            // No create operation available, clean up and return null
+            // No create operation available, clean up and fail
             let _ = Box::from_raw(new_dentry_ptr);
-            return core::ptr::null_mut();
+            return Err(Errno::EPERM);
         }
 
         // Add to parent's subdirs
@@ -258,7 +399,289 @@ pub fn create_file(parent: *mut Dentry, name: &str, mode: Mode, uid: Uid, gid: G
             .d_subdirs
             .insert(String::from(name), new_dentry_ptr);
 
-        new_dentry_ptr
+        Ok(new_dentry_ptr)
+    }
+}
+
+// Inode operations of the directory `dir`, which is about to have an entry added or removed.
+unsafe fn dir_inode_operations(dir: *mut Dentry) -> KResult<&'static InodeOperations> {
+    if dir.is_null() {
+        return Err(Errno::ENOENT);
+    }
+    let dir_inode = (*dir).d_inode;
+    if !is_dir(dir_inode) {
+        return Err(Errno::ENOTDIR);
+    }
+    (*dir_inode).inode_operations.ok_or(Errno::EPERM)
+}
+
+// A dentry for `name` in `parent` that no inode has been attached to yet.
+fn new_child_dentry(parent: *mut Dentry, name: &str) -> *mut Dentry {
+    let parent_ref = unsafe { &*parent };
+    Box::into_raw(Box::new(Dentry {
+        d_name: String::from(name),
+        d_inode: core::ptr::null_mut(),
+        d_sb: parent_ref.d_sb,
+        d_op: parent_ref.d_op,
+        d_parent: parent,
+        d_subdirs: BTreeMap::new(),
+        d_count: 0,
+    }))
+}
+
+pub fn unlink(parent: *mut Dentry, name: &str) -> KResult<()> {
+    unsafe {
+        let inode_op = dir_inode_operations(parent)?;
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        let parent_ref = &mut *parent;
+        let dentry = *parent_ref.d_subdirs.get(name).ok_or(Errno::ENOENT)?;
+        if is_dir((*dentry).d_inode) {
+            return Err(Errno::EISDIR);
+        }
+
+        let unlink_fn = inode_op.unlink.ok_or(Errno::EPERM)?;
+        errno::check(unlink_fn(parent_ref.d_inode, dentry))?;
+
+        parent_ref.d_subdirs.remove(name);
+        let _ = Box::from_raw(dentry);
+        Ok(())
+    }
+}
+
+pub fn rmdir(parent: *mut Dentry, name: &str) -> KResult<()> {
+    unsafe {
+        let inode_op = dir_inode_operations(parent)?;
+        match name {
+            "." => return Err(Errno::EINVAL),
+            ".." => return Err(Errno::ENOTEMPTY),
+            _ => {}
+        }
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        let parent_ref = &mut *parent;
+        let dentry = *parent_ref.d_subdirs.get(name).ok_or(Errno::ENOENT)?;
+        if !is_dir((*dentry).d_inode) {
+            return Err(Errno::ENOTDIR);
+        }
+        if !(*dentry).d_subdirs.is_empty() {
+            return Err(Errno::ENOTEMPTY);
+        }
+        if (*dentry).d_count > 0 {
+            return Err(Errno::EBUSY);
+        }
+
+        let rmdir_fn = inode_op.rmdir.ok_or(Errno::EPERM)?;
+        errno::check(rmdir_fn(parent_ref.d_inode, dentry))?;
+
+        parent_ref.d_subdirs.remove(name);
+        let _ = Box::from_raw(dentry);
+        Ok(())
+    }
+}
+
+// Give the inode of `old` another name, `name` in `new_parent`.
+pub fn link(old: *mut Dentry, new_parent: *mut Dentry, name: &str) -> KResult<*mut Dentry> {
+    unsafe {
+        if old.is_null() || (*old).d_inode.is_null() {
+            return Err(Errno::ENOENT);
+        }
+        if is_dir((*old).d_inode) {
+            return Err(Errno::EPERM);
+        }
+
+        let inode_op = dir_inode_operations(new_parent)?;
+        if name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        let parent_ref = &mut *new_parent;
+        if parent_ref.d_subdirs.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        if parent_ref.d_sb != (*old).d_sb {
+            return Err(Errno::EXDEV);
+        }
+
+        let link_fn = inode_op.link.ok_or(Errno::EPERM)?;
+        let new_dentry_ptr = new_child_dentry(new_parent, name);
+        if let Err(err) = errno::check(link_fn(old, parent_ref.d_inode, new_dentry_ptr)) {
+            let _ = Box::from_raw(new_dentry_ptr);
+            return Err(err);
+        }
+
+        parent_ref
+            .d_subdirs
+            .insert(String::from(name), new_dentry_ptr);
+
+        Ok(new_dentry_ptr)
+    }
+}
+
+pub const PATH_MAX: usize = 4096;
+
+// Create `name` in `parent` as a symbolic link to `target`. The target is stored as given and
+// not resolved.
+pub fn symlink(parent: *mut Dentry, name: &str, target: &str) -> KResult<*mut Dentry> {
+    unsafe {
+        let inode_op = dir_inode_operations(parent)?;
+        if name.len() > NAME_MAX || target.len() >= PATH_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        if target.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+
+        let parent_ref = &mut *parent;
+        if parent_ref.d_subdirs.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+
+        let symlink_fn = inode_op.symlink.ok_or(Errno::EPERM)?;
+
+        // The filesystem gets the target as a C string.
+        let mut symname = Vec::with_capacity(target.len() + 1);
+        symname.extend_from_slice(target.as_bytes());
+        symname.push(0);
+
+        let new_dentry_ptr = new_child_dentry(parent, name);
+        let result = symlink_fn(parent_ref.d_inode, new_dentry_ptr, symname.as_ptr());
+        if let Err(err) = errno::check(result) {
+            let _ = Box::from_raw(new_dentry_ptr);
+            return Err(err);
+        }
+
+        parent_ref
+            .d_subdirs
+            .insert(String::from(name), new_dentry_ptr);
+
+        Ok(new_dentry_ptr)
+    }
+}
+
+// Move `old_name` in `old_parent` to `new_name` in `new_parent`, replacing whatever is there.
+pub fn rename(
+    old_parent: *mut Dentry,
+    old_name: &str,
+    new_parent: *mut Dentry,
+    new_name: &str,
+) -> KResult<()> {
+    unsafe {
+        let inode_op = dir_inode_operations(old_parent)?;
+        dir_inode_operations(new_parent)?;
+        if old_name.len() > NAME_MAX || new_name.len() > NAME_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        if matches!(old_name, "." | "..") || matches!(new_name, "." | "..") {
+            return Err(Errno::EINVAL);
+        }
+
+        let old = *(*old_parent).d_subdirs.get(old_name).ok_or(Errno::ENOENT)?;
+        if (*old_parent).d_sb != (*new_parent).d_sb {
+            return Err(Errno::EXDEV);
+        }
+
+        let old_is_dir = is_dir((*old).d_inode);
+        if old_is_dir {
+            // A directory can't be moved into itself or one of its subdirectories.
+            let mut ancestor = new_parent;
+            while !ancestor.is_null() {
+                if ancestor == old {
+                    return Err(Errno::EINVAL);
+                }
+                ancestor = (*ancestor).d_parent;
+            }
+        }
+
+        let target = (*new_parent).d_subdirs.get(new_name).copied();
+        if let Some(target) = target {
+            // Renaming a file onto another link to it does nothing.
+            if target == old || (*target).d_inode == (*old).d_inode {
+                return Ok(());
+            }
+            let target_is_dir = is_dir((*target).d_inode);
+            if old_is_dir && !target_is_dir {
+                return Err(Errno::ENOTDIR);
+            }
+            if !old_is_dir && target_is_dir {
+                return Err(Errno::EISDIR);
+            }
+            if target_is_dir && !(*target).d_subdirs.is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            if (*target).d_count > 0 {
+                return Err(Errno::EBUSY);
+            }
+        }
+
+        let rename_fn = inode_op.rename.ok_or(Errno::EPERM)?;
+        let new_dentry_ptr = target.unwrap_or_else(|| new_child_dentry(new_parent, new_name));
+        let result = rename_fn(
+            (*old_parent).d_inode,
+            old,
+            (*new_parent).d_inode,
+            new_dentry_ptr,
+        );
+        if let Err(err) = errno::check(result) {
+            if target.is_none() {
+                let _ = Box::from_raw(new_dentry_ptr);
+            }
+            return Err(err);
+        }
+
+        // The filesystem has dropped the replaced inode; move the old dentry into its place.
+        (*new_parent).d_subdirs.remove(new_name);
+        let _ = Box::from_raw(new_dentry_ptr);
+        (*old_parent).d_subdirs.remove(old_name);
+
+        let old_ref = &mut *old;
+        old_ref.d_name = String::from(new_name);
+        old_ref.d_parent = new_parent;
+        (*new_parent).d_subdirs.insert(String::from(new_name), old);
+
+        Ok(())
+    }
+}
+
+// Read the target of the symbolic link `dentry`.
+pub fn read_link(dentry: *mut Dentry) -> KResult<String> {
+    unsafe {
+        let inode = (*dentry).d_inode;
+        if !is_symlink(inode) {
+            return Err(Errno::EINVAL);
+        }
+        let readlink_fn = (*inode)
+            .inode_operations
+            .and_then(|ops| ops.readlink)
+            .ok_or(Errno::EINVAL)?;
+
+        let mut buf = vec![0u8; (*inode).i_size as usize];
+        let len = errno::check(readlink_fn(dentry, buf.as_mut_ptr(), buf.len()))?;
+        buf.truncate(len);
+        String::from_utf8(buf).map_err(|_| Errno::EINVAL)
+    }
+}
+
+// Take a reference that keeps a directory from being removed while tasks are using it. Null is
+// passed through, so unset working directories need no special casing.
+pub fn dget(dentry: *mut Dentry) -> *mut Dentry {
+    if !dentry.is_null() {
+        unsafe {
+            (*dentry).d_count += 1;
+        }
+    }
+    dentry
+}
+
+pub fn dput(dentry: *mut Dentry) {
+    if !dentry.is_null() {
+        unsafe {
+            (*dentry).d_count = (*dentry).d_count.saturating_sub(1);
+        }
     }
 }
 
@@ -270,61 +693,113 @@ pub fn allocate_empty_dentry(name: &str) -> *mut Dentry {
         d_op: None,
         d_parent: core::ptr::null_mut(),
         d_subdirs: BTreeMap::new(),
+        d_count: 0,
     });
     Box::into_raw(dentry)
 }
 
-pub static mut INODES_LIST: BTreeMap<u64, *mut Inode> = BTreeMap::new();
-pub static mut NEXT_INODE_NUMBER: u64 = 2; // Start at 2 since 1 is reserved for root
+// Every live inode by number. The inodes themselves are owned by the dentry trees.
+pub struct InodeRef(pub *mut Inode);
+
+unsafe impl Send for InodeRef {}
+
+pub static INODES_LIST: SpinLock<BTreeMap<u64, InodeRef>> = SpinLock::new(BTreeMap::new());
+// Where the search for a free number starts. Only updated with INODES_LIST locked.
+static NEXT_INODE_NUMBER: AtomicU64 = AtomicU64::new(1);
 pub static MAX_INODES: u64 = 65536;
 pub fn allocate_empty_inode(mode: Mode, uid: Uid, gid: Gid, sb: *mut SuperBlock) -> *mut Inode {
-    let ino = unsafe {
-        while INODES_LIST.contains_key(&NEXT_INODE_NUMBER) {
-            NEXT_INODE_NUMBER += 1;
-            if NEXT_INODE_NUMBER == MAX_INODES {
-                NEXT_INODE_NUMBER = 1;
-            }
+    let mut inodes = INODES_LIST.lock();
+    let mut ino = NEXT_INODE_NUMBER.load(Ordering::Relaxed);
+    while inodes.contains_key(&ino) {
+        ino += 1;
+        if ino == MAX_INODES {
+            ino = 1;
         }
-        NEXT_INODE_NUMBER
-    };
+    }
+    NEXT_INODE_NUMBER.store(ino, Ordering::Relaxed);
+
+    let now = time::now();
+    let inode = Box::new(Inode {
+        i_ino: ino,
+        i_count: 0,
+        i_nlink: 1,
+        i_mode: mode,
+        i_uid: uid,
+        i_gid: gid,
+        i_size: 0,
+        i_atime: now,
+        i_mtime: now,
+        i_ctime: now,
+        i_sb: sb,
+        file_operations: None,
+        inode_operations: None,
+        i_dentry: LinkedList::new(),
+        i_private: core::ptr::null_mut(),
+    });
+    let inode_ptr = Box::into_raw(inode);
+    inodes.insert(ino, InodeRef(inode_ptr));
+    inode_ptr
+}
 
-    unsafe {
-        let inode = Box::new(Inode {
-            i_ino: ino,
-            i_count: 1,
-            i_mode: mode,
-            i_uid: uid,
-            i_gid: gid,
-            i_size: 0,
-            i_sb: sb,
-            file_operations: None,
-            inode_operations: None,
-            i_dentry: LinkedList::new(),
-            i_private: core::ptr::null_mut(),
-        });
-        let inode_ptr = Box::into_raw(inode);
-        INODES_LIST.insert(ino, inode_ptr);
-        inode_ptr
+// Free `inode` and its filesystem data.
+unsafe fn free_inode(inode: *mut Inode) {
+    let inode_ref = &*inode;
+    if let Some(drop_inode) = (*inode_ref.i_sb).s_op.and_then(|ops| ops.drop_inode) {
+        drop_inode(inode);
+    }
+    // Only give the number back once the data filed under it is gone.
+    INODES_LIST.lock().remove(&inode_ref.i_ino);
+    let _ = Box::from_raw(inode);
+}
+
+// An inode lives on while it is linked into the tree or open; an unlinked file stays readable
+// through the descriptors that still have it open.
+unsafe fn free_inode_if_unused(inode: *mut Inode) {
+    let inode_ref = &*inode;
+    if inode_ref.i_nlink == 0 && inode_ref.i_count == 0 {
+        free_inode(inode);
     }
 }
 
+// Drop an open reference to `inode`.
+pub unsafe fn put_inode(inode: *mut Inode) {
+    let inode_ref = &mut *inode;
+    inode_ref.i_count = inode_ref.i_count.saturating_sub(1);
+    free_inode_if_unused(inode);
+}
+
+// Drop a link to `inode` from the directory tree. Directories pass their full count, as removing
+// one takes its "." entry along with it.
+pub unsafe fn drop_nlink(inode: *mut Inode, count: u32) {
+    let inode_ref = &mut *inode;
+    inode_ref.i_nlink = inode_ref.i_nlink.saturating_sub(count);
+    free_inode_if_unused(inode);
+}
+
 // Helper functions for file operations
-pub fn open_file(dentry: *mut Dentry, mode: FMode) -> Option<Box<File>> {
+pub const FMODE_READ: u32 = 0o1;
+pub const FMODE_WRITE: u32 = 0o2;
+
+pub fn open_file(dentry: *mut Dentry, mode: FMode) -> KResult<Box<File>> {
     unsafe {
         if dentry.is_null() {
-            return None;
+            return Err(Errno::ENOENT);
         }
 
         let dentry_ref = &*dentry;
         if dentry_ref.d_inode.is_null() {
-            return None;
+            return Err(Errno::ENOENT);
         }
 
         let inode = dentry_ref.d_inode;
         let inode_ref = &mut *inode;
 
+        if is_dir(inode) && mode.0 & FMODE_WRITE != 0 {
+            return Err(Errno::EISDIR);
+        }
+
         if inode_ref.file_operations.is_none() {
-            return None;
+            return Err(Errno::ENODEV);
         }
 
         // Increment reference count
@@ -340,62 +815,99 @@ pub fn open_file(dentry: *mut Dentry, mode: FMode) -> Option<Box<File>> {
         // Call open operation if available
         if let Some(open_fn) = file_ops.open {
             let result = open_fn(inode, file.as_mut() as *mut File);
-            if result < 0 {
+            if let Err(err) = errno::check(result) {
                 // Open failed, decrement reference count
                 inode_ref.i_count -= 1;
-                return None;
+                return Err(err);
             }
         }
 
-        Some(file)
+        Ok(file)
     }
 }
 
-pub fn read_file(file: &mut File, buf: &mut [u8]) -> isize {
+pub fn read_file(file: &mut File, buf: &mut [u8]) -> KResult<usize> {
     unsafe {
-        if file.f_inode.is_null() {
-            return -1;
+        if file.f_inode.is_null() || file.f_mode.0 & FMODE_READ == 0 {
+            return Err(Errno::EBADF);
         }
 
         let inode_ref = &*file.f_inode;
         if inode_ref.file_operations.is_none() {
-            return -1;
+            return Err(Errno::EINVAL);
         }
 
         let file_ops = inode_ref.file_operations.unwrap();
         if let Some(read_fn) = file_ops.read {
-            read_fn(
+            errno::check(read_fn(
                 file as *mut File,
                 buf.as_mut_ptr(),
                 buf.len(),
                 &mut file.f_pos,
-            )
+            ))
         } else {
-            -1
+            Err(Errno::EINVAL)
         }
     }
 }
 
-pub fn write_file(file: &mut File, buf: &[u8]) -> isize {
+pub fn write_file(file: &mut File, buf: &[u8]) -> KResult<usize> {
     unsafe {
-        if file.f_inode.is_null() {
-            return -1;
+        if file.f_inode.is_null() || file.f_mode.0 & FMODE_WRITE == 0 {
+            return Err(Errno::EBADF);
         }
 
         let inode_ref = &*file.f_inode;
         if inode_ref.file_operations.is_none() {
-            return -1;
+            return Err(Errno::EINVAL);
         }
 
         let file_ops = inode_ref.file_operations.unwrap();
         if let Some(write_fn) = file_ops.write {
-            write_fn(file as *mut File, buf.as_ptr(), buf.len(), &mut file.f_pos)
+            errno::check(write_fn(
+                file as *mut File,
+                buf.as_ptr(),
+                buf.len(),
+                &mut file.f_pos,
+            ))
         } else {
-            -1
+            Err(Errno::EINVAL)
         }
     }
 }
 
+// The filesystem's hook for mapping pages of `file`, if it can be mapped at all.
+fn mmap_page_op(file: &File) -> KResult<MmapPageFn> {
+    if file.f_inode.is_null() {
+        return Err(Errno::EBADF);
+    }
+    if is_dir(file.f_inode) {
+        return Err(Errno::ENODEV);
+    }
+    unsafe { (*file.f_inode).file_operations }
+        .and_then(|ops| ops.mmap_page)
+        .ok_or(Errno::ENODEV)
+}
+
+// Fails unless `file_page` works on `file`, e.g. for mmap to check before any page is touched.
+pub fn check_mappable(file: &File) -> KResult<()> {
+    mmap_page_op(file).map(|_| ())
+}
+
+// Physical frame holding page `index` of an open file, so that it can be mapped into user space.
+pub fn file_page(file: &File, index: u64) -> KResult<PhysFrame> {
+    let mmap_page = mmap_page_op(file)?;
+    unsafe {
+        let mut phys = 0;
+        errno::check(mmap_page(
+            file as *const File as *mut File,
+            index,
+            &mut phys,
+        ))?;
+        Ok(PhysFrame::containing_address(PhysAddr::new(phys)))
+    }
+}
+
 // Recursively free a dentry and all its children
 unsafe fn free_dentry_tree(dentry: *mut Dentry) {
     if dentry.is_null() {
@@ -412,23 +924,15 @@ unsafe fn free_dentry_tree(dentry: *mut Dentry) {
         free_dentry_tree(child_dentry);
     }
 
-    // Free the inode if it exists
+    // Drop the inode once its last link in the tree is gone
     if !dentry_ref.d_inode.is_null() {
         let inode = dentry_ref.d_inode;
-        let inode_ref = &*inode;
-
-        // Remove from inode's dentry list
-        // Note: We can't easily remove from LinkedList, so we'll just clear it
-        // The inode will be freed separately
-
-        // Remove from global inode list
-        INODES_LIST.remove(&inode_ref.i_ino);
-
-        let super_ops = (*inode_ref.i_sb).s_op.unwrap();
-        super_ops.drop_inode.unwrap()(inode);
-
-        // Free the inode
-        let _ = Box::from_raw(inode);
+        let inode_ref = &mut *inode;
+        inode_ref.remove_dentry(dentry);
+        if inode_ref.i_dentry.is_empty() {
+            // Inodes that are still open are left to the last close.
+            drop_nlink(inode, inode_ref.i_nlink);
+        }
     }
 
     // Free the superblock if this is the root dentry
@@ -451,15 +955,7 @@ pub fn close_file(mut file: Box<File>) {
             return;
         }
 
-        let inode_ref = &mut *file.f_inode;
-
-        // Decrement reference count first
-        let was_last = if inode_ref.i_count > 0 {
-            inode_ref.i_count -= 1;
-            inode_ref.i_count == 0
-        } else {
-            false
-        };
+        let inode_ref = &*file.f_inode;
 
         // Call release operation if available
         if let Some(file_ops) = inode_ref.file_operations {
@@ -469,54 +965,44 @@ pub fn close_file(mut file: Box<File>) {
             }
         }
 
-        // If this was the last reference, free filesystem-specific data
-        // For RAMFS, this will free the file data
-        if was_last {
-            // Check if this is a RAMFS inode by comparing file_operations pointer
-            use crate::fs::ramfs::ramfs_file_operations;
-            if let Some(file_ops) = inode_ref.file_operations {
-                if core::ptr::eq(file_ops, &ramfs_file_operations::RAMFS_FILE_OPERATIONS) {
-                    use crate::fs::ramfs::ramfs_data;
-                    ramfs_data::ramfs_try_remove_data(inode_ref.i_ino);
-                }
-            }
-        }
+        // Closing the last descriptor of an unlinked file frees it and its data
+        put_inode(file.f_inode);
 
         // File is dropped here
     }
 }
 
-// Unmount a filesystem
-pub fn unmount_filesystem(root_dentry: *mut Dentry) -> i32 {
+// Tear down a filesystem instance along with all its dentries, inodes and data. Callers make
+// sure nothing uses it anymore and that it is no longer mounted.
+pub fn unmount_filesystem(root_dentry: *mut Dentry) -> KResult<()> {
     unsafe {
         if root_dentry.is_null() {
-            return -1;
+            return Err(Errno::EINVAL);
         }
 
-        let dentry_ref = &*root_dentry;
-
-        // Get the superblock
-        if dentry_ref.d_sb.is_null() {
-            return -1;
+        let sb = (*root_dentry).d_sb;
+        if sb.is_null() {
+            return Err(Errno::EINVAL);
         }
 
-        // Call filesystem-specific kill_sb if available
-        // Find the filesystem by checking the root dentry's inode
-        if !dentry_ref.d_inode.is_null() {
-            let inode_ref = &*dentry_ref.d_inode;
+        if let Some(put_super) = (*sb).s_op.and_then(|ops| ops.put_super) {
+            put_super(sb);
+        }
 
-            let s_ops = (*inode_ref.i_sb).s_op.unwrap();
-            s_ops.put_super.unwrap()(inode_ref.i_sb);
+        let kill_sb = (*sb).s_fs.and_then(|fs| {
+            with_filesystem(fs.name, |fs| {
+                let supers = core::mem::take(&mut fs.fs_supers);
+                fs.fs_supers = supers.into_iter().filter(|&s| s != sb).collect();
+                fs.kill_sb
+            })
+        });
+        if let Some(kill_sb) = kill_sb.flatten() {
+            kill_sb(&mut *sb);
         }
 
         // Free the entire dentry tree (this will free all inodes, dentries, and data)
         free_dentry_tree(root_dentry);
 
-        // Clear ROOT_DENTRY if it matches
-        if ROOT_DENTRY == root_dentry {
-            ROOT_DENTRY = core::ptr::null_mut();
-        }
-
-        0
+        Ok(())
     }
 }
@@ -1,3 +1,5 @@
+use crate::percpu;
+use crate::sync::OnceCell;
 use x86_64::instructions::segmentation::CS;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{Segment, DS, ES, SS};
@@ -5,57 +7,35 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-pub const GDT_KERNEL_CODE: usize = 0;
-pub const GDT_KERNEL_DATA: usize = 1;
-pub const GDT_USER_CODE32: usize = 2;
-pub const GDT_USER_DATA: usize = 3;
-pub const GDT_USER_CODE: usize = 4;
-pub const GDT_TSS: usize = 5;
-
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
+// Set by the first CPU through `init_gdt`.
+static SELECTORS: OnceCell<Selectors> = OnceCell::new();
 
-pub static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+pub fn selectors() -> &'static Selectors {
+    SELECTORS.get().expect("GDT not initialized")
+}
 
-pub static mut SELECTORS: Selectors = Selectors {
-    kernel_code_selector: SegmentSelector::new(
-        GDT_KERNEL_CODE as u16,
-        x86_64::PrivilegeLevel::Ring0,
-    ),
-    kernel_data_selector: SegmentSelector::new(
-        GDT_KERNEL_DATA as u16,
-        x86_64::PrivilegeLevel::Ring0,
-    ),
-    user_code32_selector: SegmentSelector::new(
-        GDT_USER_CODE32 as u16,
-        x86_64::PrivilegeLevel::Ring3,
-    ),
-    user_code_selector: SegmentSelector::new(GDT_USER_CODE as u16, x86_64::PrivilegeLevel::Ring3),
-    user_data_selector: SegmentSelector::new(GDT_USER_DATA as u16, x86_64::PrivilegeLevel::Ring3),
-    tss_selector: SegmentSelector::new(GDT_TSS as u16, x86_64::PrivilegeLevel::Ring0),
-};
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
 
-pub fn init_tss() -> () {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// The boot CPU's double fault stack. Application processors get theirs from the heap.
+static mut BSP_DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
-        let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        let stack_end = stack_start + STACK_SIZE as u64;
-        stack_end
-    };
+pub fn init_tss() {
+    let stack_start = VirtAddr::from_ptr(&raw const BSP_DOUBLE_FAULT_STACK);
+    init_tss_with_stack(stack_start + DOUBLE_FAULT_STACK_SIZE as u64);
+}
 
-    const KERNEL_STACK_SIZE: usize = 4096 * 5;
-    static mut KERNEL_STACK: [u8; KERNEL_STACK_SIZE] = [0; KERNEL_STACK_SIZE];
-    let kernel_stack_start = VirtAddr::from_ptr(&raw const KERNEL_STACK);
-    let kernel_stack_end = kernel_stack_start + KERNEL_STACK_SIZE as u64;
-    tss.privilege_stack_table[0] = kernel_stack_end;
+// Set up the calling CPU's TSS. The ring 0 stack is filled in by the scheduler once a task runs.
+pub fn init_tss_with_stack(double_fault_stack_top: VirtAddr) {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_top;
+    percpu::this_cpu().tss = tss;
+}
 
-    unsafe {
-        TSS = tss;
-    }
+// Stack the CPU switches to when an interrupt arrives in ring 3.
+pub fn set_kernel_stack(stack_top: u64) {
+    percpu::this_cpu().tss.privilege_stack_table[0] = VirtAddr::new(stack_top);
 }
 
 pub struct Selectors {
@@ -67,25 +47,33 @@ pub struct Selectors {
     pub tss_selector: SegmentSelector,
 }
 
-pub fn init_gdt() -> () {
-    #[allow(static_mut_refs)]
-    unsafe {
-        let kernel_code_selector = GDT.append(Descriptor::kernel_code_segment());
-        let kernel_data_selector = GDT.append(Descriptor::kernel_data_segment());
-        let user_code32_selector = GDT.append(Descriptor::user_code_segment());
-        let user_data_selector = GDT.append(Descriptor::user_data_segment());
-        let user_code_selector = GDT.append(Descriptor::user_code_segment());
-        let tss_selector = GDT.append(Descriptor::tss_segment(&TSS));
+// Load the calling CPU's GDT and TSS. Every CPU builds the same layout, so the selectors the
+// first one stores hold for all of them.
+pub fn init_gdt() {
+    // Both live in the per-CPU area, which is never freed.
+    let gdt: &'static mut GlobalDescriptorTable = &mut percpu::this_cpu().gdt;
+    let tss: &'static TaskStateSegment = &percpu::this_cpu().tss;
 
-        GDT.load();
+    *gdt = GlobalDescriptorTable::new();
+    let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
+    let user_code32_selector = gdt.append(Descriptor::user_code_segment());
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
 
-        SELECTORS.kernel_code_selector = kernel_code_selector;
-        SELECTORS.kernel_data_selector = kernel_data_selector;
-        SELECTORS.user_code32_selector = user_code32_selector;
-        SELECTORS.user_data_selector = user_data_selector;
-        SELECTORS.user_code_selector = user_code_selector;
-        SELECTORS.tss_selector = tss_selector;
+    gdt.load();
 
+    let _ = SELECTORS.set(Selectors {
+        kernel_code_selector,
+        kernel_data_selector,
+        user_code32_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    });
+
+    unsafe {
         CS::set_reg(kernel_code_selector);
         SS::set_reg(kernel_data_selector);
         DS::set_reg(kernel_data_selector);
@@ -4,45 +4,78 @@ use x86_64::structures::idt::{
     DivergingHandlerFuncWithErrCode, InterruptDescriptorTable, PageFaultErrorCode,
 };
 
-use crate::interrupt_idx::InterruptIndex;
-use crate::interrupts::PICS;
+use crate::interrupt_idx::{FIRST_IRQ_VECTOR, SPURIOUS_VECTOR};
+use crate::interrupts;
 use crate::klog;
+use crate::scheduler;
+use crate::sync::OnceCell;
+use crate::task;
+use crate::task::TrapFrame;
 use crate::time;
+use crate::uaccess;
+use crate::uaccess::USER_SPACE_END;
 use x86_64::registers::control::Cr2;
+use x86_64::PrivilegeLevel;
+use x86_64::VirtAddr;
+
+static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::new();
+
+fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.page_fault.set_handler_fn(page_fault);
+    idt.invalid_tss.set_handler_fn(invalid_tss);
+    idt.cp_protection_exception
+        .set_handler_fn(cp_protection_exception);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode);
+    idt.segment_not_present.set_handler_fn(segment_not_present);
+    idt.divide_error.set_handler_fn(divide_error);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
 
-static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
-
-pub fn init_idt() {
-    #[allow(static_mut_refs)]
     unsafe {
-        IDT.breakpoint.set_handler_fn(breakpoint_handler);
-        IDT.page_fault.set_handler_fn(page_fault);
-        IDT.invalid_tss.set_handler_fn(invalid_tss);
-        IDT.cp_protection_exception
-            .set_handler_fn(cp_protection_exception);
-        IDT.general_protection_fault
-            .set_handler_fn(general_protection_fault);
-        IDT.invalid_opcode.set_handler_fn(invalid_opcode);
-        IDT.segment_not_present.set_handler_fn(segment_not_present);
-        IDT.divide_error.set_handler_fn(divide_error);
-        IDT.stack_segment_fault.set_handler_fn(stack_segment_fault);
-
         let handler = {
             let ptr = double_fault_handler as *const ();
             mem::transmute::<*const (), DivergingHandlerFuncWithErrCode>(ptr)
         };
-        IDT.double_fault.set_handler_fn(handler).set_stack_index(0);
+        idt.double_fault.set_handler_fn(handler).set_stack_index(0);
 
-        IDT[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        IDT[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
-        IDT.load();
+        // Device interrupts and IPIs all go through the stubs in `interrupts`, which hand them to
+        // whatever was registered with `request_irq`.
+        for vector in FIRST_IRQ_VECTOR..SPURIOUS_VECTOR {
+            idt[vector].set_handler_addr(VirtAddr::new(interrupts::irq_stub(vector)));
+        }
+    }
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
+    idt
+}
+
+// Every CPU loads the same table; the first one builds it.
+pub fn init_idt() {
+    IDT.get_or_init(build_idt).load();
+}
+
+// Returning from a fault re-runs the faulting instruction, so a task that raised one can't be
+// resumed; kill it the way the matching signal's default action would.
+fn kill_user_task(stack_frame: &InterruptStackFrame, what: &str, signal: u32) {
+    if stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        klog!(
+            Info,
+            "pid {} killed by {}, rip {:#x}",
+            task::getpid(),
+            what,
+            stack_frame.instruction_pointer.as_u64()
+        );
+        task::exit_current(signal);
     }
 }
 
 extern "x86-interrupt" fn cp_protection_exception(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    kill_user_task(&stack_frame, "control protection exception", task::SIGSEGV);
     klog!(
         Info,
         "Coprocessor protection exception. Error code: {}",
@@ -50,14 +83,16 @@ extern "x86-interrupt" fn cp_protection_exception(
     );
 }
 
-extern "x86-interrupt" fn invalid_opcode(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
+    kill_user_task(&stack_frame, "invalid opcode", task::SIGILL);
     klog!(Error, "Invalid opcode.");
 }
 
 extern "x86-interrupt" fn general_protection_fault(
-    _stack_frame: InterruptStackFrame,
+    stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    kill_user_task(&stack_frame, "general protection fault", task::SIGSEGV);
     klog!(
         Error,
         "General protection fault. Error code: {:?}",
@@ -65,15 +100,18 @@ extern "x86-interrupt" fn general_protection_fault(
     );
 }
 
-extern "x86-interrupt" fn segment_not_present(_stack_frame: InterruptStackFrame, error_code: u64) {
+extern "x86-interrupt" fn segment_not_present(stack_frame: InterruptStackFrame, error_code: u64) {
+    kill_user_task(&stack_frame, "segment not present", task::SIGBUS);
     klog!(Error, "Segment not present. Error code: {}", error_code);
 }
 
-extern "x86-interrupt" fn stack_segment_fault(_stack_frame: InterruptStackFrame, error_code: u64) {
+extern "x86-interrupt" fn stack_segment_fault(stack_frame: InterruptStackFrame, error_code: u64) {
+    kill_user_task(&stack_frame, "stack segment fault", task::SIGBUS);
     klog!(Error, "Stack segment fault. Error code: {}", error_code);
 }
 
-extern "x86-interrupt" fn divide_error(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn divide_error(stack_frame: InterruptStackFrame) {
+    kill_user_task(&stack_frame, "divide error", task::SIGFPE);
     klog!(Error, "Divide error.");
 }
 
@@ -82,22 +120,53 @@ extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn page_fault(
-    _stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     page_fault_error_code: PageFaultErrorCode,
 ) {
     let fault_addr = Cr2::read();
+    let user_mode = page_fault_error_code.contains(PageFaultErrorCode::USER_MODE);
+    let mut signal = task::SIGSEGV;
+
+    // Copy-on-write after fork, or a page of a VMA that has not been touched yet.
+    if let Ok(addr) = fault_addr {
+        if addr.as_u64() < USER_SPACE_END {
+            let write = page_fault_error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+            let exec = page_fault_error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+            match task::resolve_page_fault(addr, write, exec) {
+                Ok(()) => return,
+                Err(sig) => signal = sig,
+            }
+        }
+    }
 
-    if fault_addr.is_err() {
-        klog!(Error, "Page fault. Error code: {:?}", page_fault_error_code);
-    } else {
-        let fault_addr: *const u8 = fault_addr.unwrap().as_ptr();
+    // A bad user pointer passed to a syscall; the copy routine reports it as EFAULT.
+    if !user_mode && uaccess::fixup_page_fault(&mut stack_frame) {
+        return;
+    }
+
+    if user_mode {
         klog!(
-            Error,
-            "Page fault at address: {:#?}, error code: {:?}",
+            Info,
+            "pid {} {} at {:?}, rip {:#x}, error code: {:?}",
+            task::getpid(),
+            if signal == task::SIGBUS {
+                "bus error"
+            } else {
+                "segfault"
+            },
             fault_addr,
+            stack_frame.instruction_pointer.as_u64(),
             page_fault_error_code
         );
+        task::exit_current(signal);
     }
+
+    panic!(
+        "Kernel page fault at {:?}, rip {:#x}, error code: {:?}",
+        fault_addr,
+        stack_frame.instruction_pointer.as_u64(),
+        page_fault_error_code
+    );
 }
 
 extern "x86-interrupt" fn invalid_tss(_stack_frame: InterruptStackFrame, error_code: u64) {
@@ -109,25 +178,23 @@ extern "x86-interrupt" fn double_fault_handler(_stack_frame: InterruptStackFrame
     loop {}
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        time::PIT_TICK_COUNT += 1;
-    }
+// The PIT interrupt only goes to the BSP.
+pub fn timer_interrupt_handler(_frame: &mut TrapFrame) {
+    time::pit_tick();
+    scheduler::tick();
+}
 
-    #[allow(static_mut_refs)]
-    unsafe {
-        PICS.notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+// Preemption tick of the application processors.
+pub fn lapic_timer_handler(_frame: &mut TrapFrame) {
+    scheduler::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// Raised when the local APIC drops an interrupt it had started to deliver. Needs no EOI.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+pub fn keyboard_interrupt_handler(_frame: &mut TrapFrame) {
     use x86_64::instructions::port::Port;
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     klog!(Debug, "Keyboard interrupt: Scancode: {:#04x}", scancode);
-
-    #[allow(static_mut_refs)]
-    unsafe {
-        PICS.notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
 }
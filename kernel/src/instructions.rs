@@ -4,7 +4,9 @@ pub const STAR: u32 = 0xC0000081;
 pub const LSTAR: u32 = 0xC0000082;
 pub const EFER: u32 = 0xC0000080;
 pub const FMASK: u32 = 0xC0000084;
+pub const GS_BASE: u32 = 0xC0000101;
 pub const KERNEL_GS_BASE: u32 = 0xC0000102;
+pub const APIC_BASE: u32 = 0x1B;
 
 pub unsafe fn rdmsr(msr: u32) -> u64 {
     let low: u32;
@@ -1,6 +1,14 @@
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// Everything from here on goes through `interrupts::request_irq`; below are the exceptions.
+pub const FIRST_IRQ_VECTOR: u8 = PIC_1_OFFSET;
+
+// Vectors raised by the local APIC itself, and IPIs.
+pub const LAPIC_TIMER_VECTOR: u8 = 0xEF;
+pub const RESCHEDULE_VECTOR: u8 = 0xF0;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -13,10 +21,22 @@ pub enum InterruptIndex {
     Lpt2 = PIC_1_OFFSET + 5,
     Floppy = PIC_1_OFFSET + 6,
     Lpt1 = PIC_1_OFFSET + 7,
+    Rtc = PIC_2_OFFSET,
+    Acpi = PIC_2_OFFSET + 1,
+    Irq10 = PIC_2_OFFSET + 2,
+    Irq11 = PIC_2_OFFSET + 3,
+    Mouse = PIC_2_OFFSET + 4,
+    Fpu = PIC_2_OFFSET + 5,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
     pub fn as_u8(self) -> u8 {
         self as u8
     }
+
+    pub fn isa_irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
 }
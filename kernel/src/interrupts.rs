@@ -1,12 +1,188 @@
-use crate::interrupt_idx::{PIC_1_OFFSET, PIC_2_OFFSET};
+use crate::acpi;
+use crate::errno::{Errno, KResult};
+use crate::idt;
+use crate::interrupt_idx::{
+    InterruptIndex, FIRST_IRQ_VECTOR, LAPIC_TIMER_VECTOR, PIC_1_OFFSET, PIC_2_OFFSET,
+    RESCHEDULE_VECTOR, SPURIOUS_VECTOR,
+};
+use crate::ioapic;
+use crate::klog;
+use crate::lapic;
+use crate::percpu;
+use crate::scheduler;
+use crate::sync::SpinLock;
+use crate::task::TrapFrame;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 use pic8259::ChainedPics;
 
-pub static mut PICS: ChainedPics = unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) };
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// ISA IRQs go through the I/O APIC; the PIC is only used if there is none.
+static USE_IOAPIC: AtomicBool = AtomicBool::new(false);
+
+// Called with the `TrapFrame` of the interrupted context. The interrupt has already been
+// acknowledged, so a handler may switch tasks.
+pub type IrqHandler = fn(&mut TrapFrame);
+
+static IRQ_HANDLERS: SpinLock<[Option<IrqHandler>; 256]> = SpinLock::new([None; 256]);
+
+// One 16-byte stub per vector from FIRST_IRQ_VECTOR on. Each saves rax, loads its vector
+// number into it and joins the common path, which saves the rest of the `TrapFrame` in the same
+// order as the other entry stubs.
+global_asm!(
+    r#"
+    .pushsection .text.irq_stubs, "ax"
+    .balign 16
+    .global irq_stubs
+irq_stubs:
+    .set irq_stub_vector, {first}
+    .rept 256 - {first}
+    .balign 16
+    push %rax
+    mov $irq_stub_vector, %eax
+    jmp irq_common
+    .set irq_stub_vector, irq_stub_vector + 1
+    .endr
+
+irq_common:
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, %rdi
+    mov %eax, %esi
+    call {dispatch}
+    jmp {ret}
+    .popsection
+"#,
+    first = const FIRST_IRQ_VECTOR,
+    dispatch = sym irq_dispatch,
+    ret = sym scheduler::interrupt_return,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static irq_stubs: u8;
+}
+
+const IRQ_STUB_SIZE: u64 = 16;
+
+// Entry point to put in the IDT for `vector`.
+pub fn irq_stub(vector: u8) -> u64 {
+    (&raw const irq_stubs) as u64 + (vector - FIRST_IRQ_VECTOR) as u64 * IRQ_STUB_SIZE
+}
+
+extern "C" fn irq_dispatch(frame: &mut TrapFrame, vector: u64) {
+    let vector = vector as u8;
+    let handler = IRQ_HANDLERS.lock()[vector as usize];
+
+    // Acknowledge before running the handler, which may switch to another task and not come
+    // back here for a while. Every source routed so far is edge-triggered, so this can't make
+    // the interrupt fire again.
+    end_of_interrupt(vector);
+
+    match handler {
+        Some(handler) => handler(frame),
+        None => klog!(Warn, "Unhandled interrupt, vector {:#x}", vector),
+    }
+}
+
+fn end_of_interrupt(vector: u8) {
+    if !USE_IOAPIC.load(Ordering::Relaxed) {
+        let mut pics = PICS.lock();
+        if pics.handles_interrupt(vector) {
+            unsafe {
+                pics.notify_end_of_interrupt(vector);
+            }
+            return;
+        }
+    }
+    lapic::eoi();
+}
+
+// Install `handler` for `vector`. Each vector has at most one handler.
+pub fn request_irq(vector: u8, handler: IrqHandler) -> KResult<()> {
+    if vector < FIRST_IRQ_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(Errno::EINVAL);
+    }
+    let mut handlers = IRQ_HANDLERS.lock();
+    if handlers[vector as usize].is_some() {
+        return Err(Errno::EBUSY);
+    }
+    handlers[vector as usize] = Some(handler);
+    Ok(())
+}
+
+pub fn free_irq(vector: u8) {
+    IRQ_HANDLERS.lock()[vector as usize] = None;
+}
+
+// Let ISA IRQ `irq` through to the calling CPU, as vector PIC_1_OFFSET + irq.
+pub fn enable_isa_irq(irq: u8) {
+    let vector = PIC_1_OFFSET + irq;
+    if USE_IOAPIC.load(Ordering::Relaxed) {
+        let (gsi, flags) = acpi::info().map_or((irq as u32, 0), |acpi| acpi.isa_irq_route(irq));
+        if !ioapic::route(gsi, vector, flags, percpu::this_cpu().apic_id) {
+            klog!(Error, "No I/O APIC input for IRQ {} (GSI {})", irq, gsi);
+        }
+        return;
+    }
+
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut mask1, mut mask2] = pics.read_masks();
+        if irq < 8 {
+            mask1 &= !(1 << irq);
+        } else {
+            mask1 &= !(1 << 2);
+            mask2 &= !(1 << (irq - 8));
+        }
+        pics.write_masks(mask1, mask2);
+    }
+}
+
+// Set up the interrupt controllers of the boot CPU and turn interrupts on. Needs the heap and
+// the ACPI tables.
 pub fn init_interrupts() {
-    #[allow(static_mut_refs)]
     unsafe {
-        PICS.initialize();
-        x86_64::instructions::interrupts::enable();
+        // Remapped even when unused, so a stray PIC interrupt can't land on an exception vector.
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.disable();
+    }
+
+    lapic::init();
+    if ioapic::init() {
+        USE_IOAPIC.store(true, Ordering::Relaxed);
+    } else {
+        klog!(Warn, "No I/O APIC found, falling back to the 8259 PIC.");
     }
+
+    request_irq(InterruptIndex::Timer.as_u8(), idt::timer_interrupt_handler)
+        .expect("timer vector taken");
+    request_irq(
+        InterruptIndex::Keyboard.as_u8(),
+        idt::keyboard_interrupt_handler,
+    )
+    .expect("keyboard vector taken");
+    request_irq(LAPIC_TIMER_VECTOR, idt::lapic_timer_handler).expect("LAPIC timer vector taken");
+    request_irq(RESCHEDULE_VECTOR, scheduler::reschedule_interrupt)
+        .expect("reschedule vector taken");
+
+    enable_isa_irq(InterruptIndex::Timer.isa_irq());
+    enable_isa_irq(InterruptIndex::Keyboard.isa_irq());
+
+    x86_64::instructions::interrupts::enable();
 }
@@ -0,0 +1,130 @@
+use crate::acpi;
+use crate::klog;
+use crate::memory;
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+
+// Registers are reached through a select/window pair, so every access goes through IO_APICS'
+// lock.
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+// MPS INTI flags as found in the MADT interrupt source overrides.
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_LEVEL: u16 = 0b11 << 2;
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            core::ptr::read_volatile((self.base + REG_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + REG_SELECT) as *mut u32, reg);
+            core::ptr::write_volatile((self.base + REG_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn read_redirection(&self, index: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION + index * 2) as u64;
+        let high = self.read(REG_REDIRECTION + index * 2 + 1) as u64;
+        low | (high << 32)
+    }
+
+    fn write_redirection(&self, index: u32, entry: u64) {
+        // Mask first so the entry is never live half-written.
+        self.write(REG_REDIRECTION + index * 2, REDIRECT_MASKED as u32);
+        self.write(REG_REDIRECTION + index * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + index * 2, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+}
+
+static IO_APICS: SpinLock<Vec<IoApic>> = SpinLock::new(Vec::new());
+
+// Map the I/O APICs listed in the MADT and mask all their inputs. Returns false if there are
+// none.
+pub fn init() -> bool {
+    let Some(acpi) = acpi::info() else {
+        return false;
+    };
+
+    let mut io_apics = IO_APICS.lock();
+    for entry in &acpi.io_apics {
+        let Some(base) = memory::map_mmio(entry.address, 0x20) else {
+            klog!(Error, "Failed to map I/O APIC {}", entry.id);
+            continue;
+        };
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+        for index in 0..io_apic.entries {
+            io_apic.write_redirection(index, REDIRECT_MASKED);
+        }
+        klog!(
+            Debug,
+            "I/O APIC {}: GSIs {}-{}",
+            entry.id,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.entries - 1
+        );
+        io_apics.push(io_apic);
+    }
+    !io_apics.is_empty()
+}
+
+// Deliver `gsi` as `vector` to the local APIC `dest_apic_id`. `flags` are the MPS INTI flags of
+// the source; 0 means ISA defaults, i.e. edge-triggered and active high.
+pub fn route(gsi: u32, vector: u8, flags: u16, dest_apic_id: u32) -> bool {
+    let io_apics = IO_APICS.lock();
+    let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) else {
+        return false;
+    };
+
+    let mut entry = vector as u64 | ((dest_apic_id as u64) << 56);
+    if flags & INTI_POLARITY_MASK == INTI_ACTIVE_LOW {
+        entry |= REDIRECT_ACTIVE_LOW;
+    }
+    if flags & INTI_TRIGGER_MASK == INTI_LEVEL {
+        entry |= REDIRECT_LEVEL;
+    }
+    io_apic.write_redirection(gsi - io_apic.gsi_base, entry);
+    true
+}
+
+pub fn set_masked(gsi: u32, masked: bool) {
+    let io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+        let index = gsi - io_apic.gsi_base;
+        let entry = io_apic.read_redirection(index);
+        if masked {
+            io_apic.write_redirection(index, entry | REDIRECT_MASKED);
+        } else {
+            io_apic.write_redirection(index, entry & !REDIRECT_MASKED);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::instructions::{rdmsr, wrmsr, APIC_BASE};
+use crate::interrupt_idx::SPURIOUS_VECTOR;
+use crate::memory;
+use crate::sync::OnceCell;
+use crate::time;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Register offsets in the xAPIC MMIO page.
+const REG_ID: u64 = 0x20;
+const REG_TPR: u64 = 0x80;
+const REG_EOI: u64 = 0xB0;
+const REG_SVR: u64 = 0xF0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_TIMER_INITIAL: u64 = 0x380;
+const REG_TIMER_CURRENT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3E0;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const SVR_ENABLE: u32 = 1 << 8;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+static BASE: OnceCell<u64> = OnceCell::new();
+// Timer ticks (with TIMER_DIVIDE_16) per PIT period, measured by `calibrate_timer`.
+static TIMER_TICKS_PER_PERIOD: AtomicU32 = AtomicU32::new(0);
+
+fn read(reg: u64) -> u32 {
+    let base = BASE.get().expect("local APIC not mapped");
+    unsafe { core::ptr::read_volatile((base + reg) as *const u32) }
+}
+
+fn write(reg: u64, value: u32) {
+    let base = BASE.get().expect("local APIC not mapped");
+    unsafe { core::ptr::write_volatile((base + reg) as *mut u32, value) }
+}
+
+// Enable the calling CPU's local APIC. The register page is the same physical address on every
+// CPU, so it is mapped once.
+pub fn init() {
+    let msr = unsafe { rdmsr(APIC_BASE) };
+    BASE.get_or_init(|| {
+        memory::map_mmio(msr & 0x000F_FFFF_FFFF_F000, 4096).expect("failed to map local APIC")
+    });
+    unsafe {
+        wrmsr(APIC_BASE, msr | APIC_BASE_ENABLE);
+    }
+
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+fn send_ipi(dest_apic_id: u32, command: u32) {
+    write(REG_ICR_HIGH, dest_apic_id << 24);
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// Fixed-delivery IPIs raising `vector` on another CPU.
+pub fn send_ipi_to(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_ASSERT | vector as u32);
+}
+
+pub fn broadcast_ipi(vector: u8) {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+// INIT and STARTUP to every other CPU, for the INIT-SIPI-SIPI sequence. `page` is the physical
+// page number the application processors start executing at, in real mode.
+pub fn broadcast_init() {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_INIT | ICR_ASSERT);
+}
+
+pub fn broadcast_startup(page: u8) {
+    send_ipi(
+        0,
+        ICR_ALL_EXCLUDING_SELF | ICR_STARTUP | ICR_ASSERT | page as u32,
+    );
+}
+
+// Measure the timer against one PIT period. Needs the PIT interrupt to be running.
+pub fn calibrate_timer() {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+
+    // Start on a tick boundary.
+    let tick = time::get_pit_tick_count();
+    while time::get_pit_tick_count() == tick {
+        core::hint::spin_loop();
+    }
+
+    write(REG_TIMER_INITIAL, u32::MAX);
+    let tick = time::get_pit_tick_count();
+    while time::get_pit_tick_count() == tick {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+
+    TIMER_TICKS_PER_PERIOD.store(elapsed, Ordering::Relaxed);
+}
+
+// Busy-wait using the timer in one-shot mode, with its interrupt masked. Only for CPUs that
+// don't use the timer for anything else.
+pub fn delay_us(us: u64) {
+    let per_period = TIMER_TICKS_PER_PERIOD.load(Ordering::Relaxed) as u64;
+    let ticks = (per_period * us * 1000 / time::pit_period_ns()).clamp(1, u32::MAX as u64);
+
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_TIMER_INITIAL, ticks as u32);
+    while read(REG_TIMER_CURRENT) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// Raise `vector` periodically at the PIT rate, so every CPU gets the same time slices.
+pub fn start_periodic_timer(vector: u8) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    write(REG_LVT_TIMER, TIMER_PERIODIC | vector as u32);
+    write(
+        REG_TIMER_INITIAL,
+        TIMER_TICKS_PER_PERIOD.load(Ordering::Relaxed),
+    );
+}
@@ -1,12 +1,16 @@
 use crate::serial::SerialPort;
 
+use crate::sync::SpinLock;
 use crate::time;
 use core::fmt::Write;
-pub static mut PORT: SerialPort = SerialPort::new(0x3F8);
-pub static mut KERNEL_LOG_LEVEL: LogLevel = LogLevel::Debug;
+use core::sync::atomic::{AtomicU8, Ordering};
+pub static PORT: SpinLock<SerialPort> = SpinLock::new(SerialPort::new(0x3F8));
+// A `LogLevel`; messages above it are dropped.
+pub static KERNEL_LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
 
 #[allow(dead_code)]
 #[derive(PartialEq, PartialOrd)]
+#[repr(u8)]
 pub enum LogLevel {
     Off = 0,
     Fatal = 1,
@@ -16,21 +20,31 @@ pub enum LogLevel {
     Debug = 5,
 }
 
-pub fn log_timestamp() -> f32 {
-    time::time_since_boot()
+// Seconds since boot with microsecond precision, as printed in front of every log line.
+pub struct Timestamp(u64);
+
+impl core::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}.{:06}",
+            self.0 / 1_000_000_000,
+            self.0 % 1_000_000_000 / 1000
+        )
+    }
+}
+
+pub fn log_timestamp() -> Timestamp {
+    Timestamp(time::monotonic_ns())
 }
 
 pub fn serial_write_fmt(args: core::fmt::Arguments) {
-    unsafe {
-        let _ = writeln!(PORT, "{}", args);
-    }
+    let _ = writeln!(PORT.lock(), "{}", args);
 }
 
 pub fn serial_write_fmt_loglevel(log_level: LogLevel, args: core::fmt::Arguments) {
-    unsafe {
-        if log_level <= KERNEL_LOG_LEVEL {
-            serial_write_fmt(args);
-        }
+    if log_level as u8 <= KERNEL_LOG_LEVEL.load(Ordering::Relaxed) {
+        serial_write_fmt(args);
     }
 }
 
@@ -46,13 +60,11 @@ macro_rules! klog {
     ($level:ident, $($arg:tt)*) => {
         $crate::logging::serial_write_fmt_loglevel(
             $crate::logging::LogLevel::$level,
-            format_args!("[{:.6}] {}", $crate::logging::log_timestamp(), format_args!($($arg)*))
+            format_args!("[{}] {}", $crate::logging::log_timestamp(), format_args!($($arg)*))
         )
     };
 }
 
 pub fn set_log_level(level: LogLevel) {
-    unsafe {
-        KERNEL_LOG_LEVEL = level;
-    }
+    KERNEL_LOG_LEVEL.store(level as u8, Ordering::Relaxed);
 }
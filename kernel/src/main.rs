@@ -3,11 +3,13 @@
 #![feature(abi_x86_interrupt)]
 #![feature(optimize_attribute)]
 #![allow(dead_code)]
-#![allow(static_mut_refs)]
 extern crate alloc;
 
+mod acpi;
 mod allocator;
 mod cpuid;
+mod elf;
+mod errno;
 mod freestanding;
 mod fs;
 mod gdt;
@@ -16,40 +18,49 @@ mod idt;
 mod instructions;
 mod interrupt_idx;
 mod interrupts;
+mod ioapic;
+mod lapic;
 mod logging;
 mod memory;
+mod mmap;
 mod panic;
+mod percpu;
+mod rtc;
+mod scheduler;
 mod serial;
+mod smp;
+mod sync;
 mod syscall;
 mod task;
 mod time;
 mod types;
+mod uaccess;
 mod userspace;
+mod vma;
 
 use alloc::format;
 use alloc::string::String;
 use bootloader_api::config::Mapping;
 use bootloader_api::{entry_point, BootInfo};
 use core::fmt::Write;
+use core::sync::atomic::Ordering;
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::VirtAddr;
 
-use crate::allocator::HeapAllocator;
+use crate::allocator::{HeapAllocator, HeapStats};
 use crate::cpuid::CpuFeatureEcx;
 use crate::logging::{set_log_level, LogLevel};
-use crate::memory::{
-    init_heap, switch_to_user_page_table, KFrameAllocator, KERNEL_PAGE_TABLE_FRAME,
-};
+use crate::memory::{init_heap, switch_to_user_page_table, KERNEL_PAGE_TABLE_FRAME};
 use crate::serial::SerialPort;
 use crate::syscall::configure_syscalls;
-use crate::task::{create_task, set_current_pid, Task};
+use crate::task::{create_task, set_current_pid};
 use crate::userspace::jump_userspace;
 
 use crate::fs::vfs;
 use crate::types::{FMode, Mode};
 
 #[global_allocator]
-static mut ALLOCATOR: HeapAllocator = HeapAllocator::new(0, 0);
+static ALLOCATOR: HeapAllocator = HeapAllocator::empty();
 
 const BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
@@ -57,10 +68,20 @@ const BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
     config
 };
 
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    let mut frame_allocator = unsafe { KFrameAllocator::new(&boot_info.memory_regions) };
+    percpu::init_this_cpu(0);
+    unsafe {
+        memory::init_frame_allocator(&boot_info.memory_regions);
+    }
+    smp::reserve_trampoline();
+    let mut frame_allocator = memory::frame_allocator();
+    let frame_stats = frame_allocator.stats();
 
     set_log_level(LogLevel::Debug);
 
@@ -70,6 +91,12 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     }
 
     klog!(Debug, "Serial port test.");
+    klog!(
+        Debug,
+        "Physical memory: {} MiB free of {} MiB usable",
+        frame_stats.free_frames * 4096 / (1024 * 1024),
+        frame_stats.total_frames * 4096 / (1024 * 1024)
+    );
 
     let cpu_info = cpuid::analyze_cpuid();
     cpuid::log_cpuid_full(&cpu_info);
@@ -87,8 +114,12 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         klog!(Fatal, "NX bit not enabled.");
     }
 
-    time::set_pit_tick_count(0);
-    klog!(Debug, "PIT frequency set to {}", time::get_pit_frequency());
+    time::set_pit_divisor(65536);
+    klog!(
+        Debug,
+        "PIT period set to {} us",
+        time::pit_period_ns() / 1000
+    );
 
     gdt::init_tss();
     klog!(Debug, "Initialized TSS.");
@@ -96,28 +127,32 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     klog!(Debug, "Initialized GDT.");
     idt::init_idt();
     klog!(Debug, "Initialized IDT.");
-    interrupts::init_interrupts();
-    klog!(Debug, "Initialized PIC.");
 
     x86_64::instructions::interrupts::int3();
 
     let mut offset_page_table: OffsetPageTable;
     if let Some(bootloader_memory_offset) = boot_info.physical_memory_offset.into_option() {
-        unsafe {
-            KERNEL_PAGE_TABLE_FRAME = bootloader_memory_offset;
-        }
-        offset_page_table = memory::init(VirtAddr::new(unsafe { KERNEL_PAGE_TABLE_FRAME }));
+        KERNEL_PAGE_TABLE_FRAME.store(bootloader_memory_offset, Ordering::Relaxed);
+        offset_page_table = memory::init(VirtAddr::new(
+            KERNEL_PAGE_TABLE_FRAME.load(Ordering::Relaxed),
+        ));
 
+        let heap_size = memory::initial_heap_size(&boot_info.memory_regions);
         init_heap(
             memory::HEAP_START,
-            1024 * 1024, // TODO: This should be dynamic and at least ~2% of the total memory.
+            heap_size,
             &mut offset_page_table,
             &mut frame_allocator,
         )
         .expect("Failed to initialize heap");
 
         unsafe {
-            ALLOCATOR = HeapAllocator::new(memory::HEAP_START, 1024 * 1024);
+            ALLOCATOR.init(
+                memory::HEAP_START,
+                heap_size,
+                memory::HEAP_MAX_SIZE,
+                memory::grow_heap,
+            );
         }
     } else {
         klog!(Fatal, "Didn't receive paging info from the bootloader.");
@@ -126,39 +161,48 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     let string: String = format!("Initialized {}.", "allocator");
     klog!(Debug, "{}", string);
+    let heap_stats = heap_stats();
+    klog!(
+        Debug,
+        "Kernel heap: {} KiB, {} bytes in use",
+        heap_stats.size / 1024,
+        heap_stats.used
+    );
+
+    match boot_info.rsdp_addr.into_option() {
+        Some(rsdp) => acpi::init(rsdp),
+        None => klog!(Warn, "No ACPI RSDP from the bootloader."),
+    }
+    interrupts::init_interrupts();
+    klog!(Debug, "Initialized interrupt controllers.");
+    time::init_clocksource();
+    time::init_realtime();
 
     configure_syscalls();
+    smp::init();
+
     let pid = create_task(0, &mut frame_allocator, offset_page_table.phys_offset());
     set_current_pid(pid);
-    let task: &mut Task = task::get_current_task().expect("Failed to get current task");
-    switch_to_user_page_table(&mut task.page_table);
+    task::with_current_task(|task| switch_to_user_page_table(&mut task.page_table));
 
     vfs::vfs_init();
+    let root = vfs::root_dentry();
     unsafe {
-        if !vfs::ROOT_DENTRY.is_null() {
-            klog!(
-                Debug,
-                "Mounted RAMFS at: {}",
-                vfs::get_full_path(vfs::ROOT_DENTRY)
-            );
+        let _vfs = vfs::lock();
+        if !root.is_null() {
+            klog!(Debug, "Mounted RAMFS at: {}", vfs::get_full_path(root));
 
             // Create /bin directory
-            let bin_dir = vfs::mkdir(
-                vfs::ROOT_DENTRY,
-                "bin",
-                Mode::from(0o40777),
-                0.into(),
-                0.into(),
-            );
+            let bin_dir_result = vfs::mkdir(root, "bin", Mode::from(0o40777), 0.into(), 0.into());
 
-            if !bin_dir.is_null() {
+            if let Ok(bin_dir) = bin_dir_result {
                 klog!(Debug, "Created /bin directory");
 
                 // Embed the init program binary
-                const INIT_PROGRAM: &[u8] = include_bytes!("../programs/init.bin");
+                const INIT_PROGRAM: &[u8] = include_bytes!("../programs/init.elf");
 
                 // Create /bin/init file
-                let init_file_dentry = vfs::create_file(
+                let init_file_result = vfs::create_file(
                     bin_dir,
                     "init",
                     Mode::from(0o100755), // Executable
@@ -166,18 +210,17 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
                     0.into(),
                 );
 
-                if !init_file_dentry.is_null() {
+                if let Ok(init_file_dentry) = init_file_result {
                     klog!(Debug, "Created /bin/init file");
 
                     // Write the embedded program to the file
-                    if let Some(mut init_file) = vfs::open_file(init_file_dentry, FMode::from(0o2))
-                    {
+                    if let Ok(mut init_file) = vfs::open_file(init_file_dentry, FMode::from(0o2)) {
                         let write_result = vfs::write_file(&mut *init_file, INIT_PROGRAM);
-                        klog!(Debug, "Wrote {} bytes to /bin/init", write_result);
+                        klog!(Debug, "Wrote {:?} bytes to /bin/init", write_result);
                         vfs::close_file(init_file);
 
                         // Read back and print info
-                        if let Some(mut init_file) =
+                        if let Ok(mut init_file) =
                             vfs::open_file(init_file_dentry, FMode::from(0o1))
                         {
                             let dentry_ref = &*init_file_dentry;
@@ -192,7 +235,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
                                 read_buffer.resize(file_size as usize, 0);
                                 let read_result = vfs::read_file(&mut *init_file, &mut read_buffer);
 
-                                if read_result > 0 {
+                                if read_result.is_ok_and(|count| count > 0) {
                                     // Print hexadecimal contents
                                     let mut hex_string = String::new();
                                     for (i, byte) in read_buffer.iter().enumerate() {
@@ -213,17 +256,15 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
                             }
                         }
                     }
-                } else {
-                    klog!(Debug, "Failed to create /bin/init file");
+                } else if let Err(err) = init_file_result {
+                    klog!(Debug, "Failed to create /bin/init file: {:?}", err);
                 }
-            } else {
-                klog!(Debug, "Failed to create /bin directory");
+            } else if let Err(err) = bin_dir_result {
+                klog!(Debug, "Failed to create /bin directory: {:?}", err);
             }
         } else {
             klog!(Fatal, "Failed to mount root filesystem");
         }
     }
-    jump_userspace(&mut frame_allocator, task);
-
-    hcf::hcf();
+    jump_userspace(&mut frame_allocator, "/bin/init");
 }
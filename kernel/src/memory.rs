@@ -1,9 +1,12 @@
+use crate::sync::SpinLock;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use core::ops::Sub;
-use x86_64::registers::control::Cr3;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
 use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::page_table::PageTable;
+use x86_64::structures::paging::page_table::{PageTable, PageTableEntry};
 use x86_64::structures::paging::{
     FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
     Size4KiB,
@@ -12,10 +15,25 @@ use x86_64::{PhysAddr, VirtAddr};
 
 pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8880_0000_0000;
 pub const HEAP_START: usize = 0xFFFF_C900_0000_0000;
+pub const HEAP_MIN_SIZE: u64 = 1024 * 1024;
+// The heap starts at ~2% of usable RAM and grows on demand up to this cap.
+pub const HEAP_MAX_SIZE: u64 = 512 * 1024 * 1024;
 pub const USERSPACE_CODE_START: u64 = 0x0000_0000_0040_0000;
 pub const USERSPACE_STACK_START: u64 = 0x7FFF_FFFF_F000;
+pub const USER_STACK_TOP: u64 = USERSPACE_STACK_START + 4096;
+// The stack grows down from the page at USERSPACE_STACK_START. USER_STACK_PAGES are mapped
+// up front, the rest of the stack area is faulted in as it is used.
+pub const USER_STACK_PAGES: u64 = 16;
+pub const USER_STACK_MAX_SIZE: u64 = 8 * 1024 * 1024;
 
-pub static mut KERNEL_PAGE_TABLE_FRAME: u64 = 0;
+pub static KERNEL_PAGE_TABLE_FRAME: AtomicU64 = AtomicU64::new(0);
+// Physical address of the bootloader-provided PML4, used whenever no user task is running. Set
+// by `init` on the boot CPU before the others start.
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_pml4() -> u64 {
+    KERNEL_PML4.load(Ordering::Relaxed)
+}
 
 fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -31,69 +49,290 @@ fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTa
 
 pub fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     unsafe {
+        KERNEL_PML4.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+        // Make the kernel honour read-only user mappings too, so that its writes into
+        // copy-on-write pages fault and get copied like user writes do.
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
+const FRAME_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+// Physical frame allocator backed by a bitmap with one bit per frame (set = in use). The bitmap
+// is carved out of the first usable region big enough to hold it and accessed through the
+// physical memory mapping, so it works before the heap exists and never allocates itself.
+pub struct KFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    // Word to start the next single-frame search at; everything before it was full last time.
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl KFrameAllocator {
+    pub unsafe fn new(memory_map: &'static MemoryRegions) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        let max_addr = usable().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(64);
+        let bitmap_bytes = ((words * 8) as u64).div_ceil(FRAME_SIZE) * FRAME_SIZE;
+
+        let bitmap_start = usable()
+            .map(|r| (r.start.div_ceil(FRAME_SIZE) * FRAME_SIZE, r.end))
+            .find(|(start, end)| *start != 0 && end - start >= bitmap_bytes)
+            .map(|(start, _)| start)
+            .expect("No usable region large enough for the frame bitmap");
+
+        let bitmap = core::slice::from_raw_parts_mut(
+            (PHYSICAL_MEMORY_OFFSET + bitmap_start) as *mut u64,
+            words,
+        );
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = KFrameAllocator {
+            bitmap,
+            frame_count,
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        for region in usable() {
+            let first = region.start.div_ceil(FRAME_SIZE) as usize;
+            let last = (region.end / FRAME_SIZE) as usize;
+            for index in first..last {
+                allocator.set_free(index);
+            }
         }
+
+        // Frame 0 stays reserved so that a null physical address is never handed out.
+        allocator.set_used(0);
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + (bitmap_bytes / FRAME_SIZE) as usize {
+            allocator.set_used(index);
+        }
+
+        allocator.total_frames = allocator.free_frames;
+        allocator
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+    fn set_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.free_frames -= 1;
+        }
     }
-}
 
-pub struct KFrameAllocator {
-    inner: BootInfoFrameAllocator,
-    free_list: Vec<PhysFrame>,
-}
+    fn set_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            self.free_frames += 1;
+        }
+    }
 
-impl KFrameAllocator {
-    pub unsafe fn new(memory_map: &'static MemoryRegions) -> Self {
-        Self {
-            inner: BootInfoFrameAllocator::init(memory_map),
-            free_list: Vec::new(),
+    // Allocate `count` physically contiguous frames whose first frame number is a multiple of
+    // `align` (in frames), e.g. for DMA buffers or 2 MiB pages.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+        let align = align.max(1);
+
+        let mut start = align;
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&i| self.is_used(i)) {
+                Some(used) => start = (used + 1).div_ceil(align) * align,
+                None => {
+                    for index in start..start + count {
+                        self.set_used(index);
+                    }
+                    return Some(PhysFrame::containing_address(PhysAddr::new(
+                        start as u64 * FRAME_SIZE,
+                    )));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        let first = (first.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            self.set_free(index);
+        }
+        self.next_word = self.next_word.min(first / 64);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.free_frames,
         }
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for KFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        if let Some(frame) = self.free_list.pop() {
-            Some(frame)
-        } else {
-            self.inner.allocate_frame()
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let word_index = (self.next_word + i) % words;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+
+            let index = word_index * 64 + (!word).trailing_zeros() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+
+            self.set_used(index);
+            self.next_word = word_index;
+            return Some(PhysFrame::containing_address(PhysAddr::new(
+                index as u64 * FRAME_SIZE,
+            )));
         }
+
+        None
     }
 }
 
 impl FrameDeallocator<Size4KiB> for KFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        self.free_list.push(frame);
+        self.deallocate_contiguous(frame, 1);
+    }
+}
+
+static FRAME_ALLOCATOR: SpinLock<Option<KFrameAllocator>> = SpinLock::new(None);
+
+pub unsafe fn init_frame_allocator(memory_map: &'static MemoryRegions) {
+    *FRAME_ALLOCATOR.lock() = Some(KFrameAllocator::new(memory_map));
+}
+
+// Handle to the global frame allocator, for code paths (syscalls, faults) that can't have one
+// passed down. The lock is only held for the duration of each call, so the handle can be passed
+// through code that ends up allocating frames somewhere else too.
+pub struct GlobalFrameAllocator;
+
+impl GlobalFrameAllocator {
+    fn with<R>(&self, f: impl FnOnce(&mut KFrameAllocator) -> R) -> R {
+        let mut guard = FRAME_ALLOCATOR.lock();
+        f(guard.as_mut().expect("Frame allocator not initialized"))
+    }
+
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.with(|fa| fa.allocate_contiguous(count, align))
+    }
+
+    pub unsafe fn deallocate_contiguous(&mut self, first: PhysFrame, count: usize) {
+        self.with(|fa| fa.deallocate_contiguous(first, count))
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.with(|fa| fa.stats())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.with(|fa| fa.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.with(|fa| fa.deallocate_frame(frame))
+    }
+}
+
+pub fn frame_allocator() -> GlobalFrameAllocator {
+    GlobalFrameAllocator
+}
+
+pub fn initial_heap_size(memory_map: &MemoryRegions) -> u64 {
+    let usable: u64 = memory_map
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+        .map(|r| r.end - r.start)
+        .sum();
+
+    ((usable / 50 + 4095) & !4095).clamp(HEAP_MIN_SIZE, HEAP_MAX_SIZE)
+}
+
+// Grow callback for the kernel heap. Mapping goes through the kernel PML4; user page tables
+// share its lower level tables for the heap range, so every address space sees the new pages.
+pub fn grow_heap(start: usize, size: usize) -> bool {
+    let mut mapper = kernel_page_table();
+    init_heap(start, size as u64, &mut mapper, &mut frame_allocator()).is_ok()
+}
+
+// Device registers are mapped uncached from here on. The window shares the heap's PML4 entry,
+// which every user page table copies, so the mappings show up in all address spaces.
+pub const MMIO_START: u64 = HEAP_START as u64 + HEAP_MAX_SIZE;
+const MMIO_END: u64 = MMIO_START + 1024 * 1024 * 1024;
+static NEXT_MMIO: SpinLock<u64> = SpinLock::new(MMIO_START);
+
+// Map `size` bytes of device memory at physical address `phys` and return the virtual address.
+pub fn map_mmio(phys: u64, size: u64) -> Option<u64> {
+    let first = phys & !(FRAME_SIZE - 1);
+    let len = (phys + size).div_ceil(FRAME_SIZE) * FRAME_SIZE - first;
+
+    let start = {
+        let mut next = NEXT_MMIO.lock();
+        if *next + len > MMIO_END {
+            return None;
+        }
+        let start = *next;
+        *next += len;
+        start
+    };
+
+    let mut flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    if crate::instructions::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
     }
+
+    let mut mapper = kernel_page_table();
+    let mut frame_allocator = frame_allocator();
+    for offset in (0..len).step_by(FRAME_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + offset));
+        let frame = PhysFrame::containing_address(PhysAddr::new(first + offset));
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut frame_allocator)
+                .ok()?
+                .flush();
+        }
+    }
+
+    Some(start + (phys - first))
+}
+
+pub fn kernel_page_table() -> OffsetPageTable<'static> {
+    let pml4_frame = PhysFrame::containing_address(PhysAddr::new(kernel_pml4()));
+    page_table_frame_to_mapper(pml4_frame, VirtAddr::new(PHYSICAL_MEMORY_OFFSET))
 }
 
 pub fn init_heap(
@@ -174,11 +413,14 @@ pub fn create_user_page_table_with_mapper(
     Ok(mapper)
 }
 
-pub fn switch_to_user_page_table(page_table: &mut OffsetPageTable) {
+pub fn page_table_frame(page_table: &OffsetPageTable) -> PhysFrame<Size4KiB> {
     let pml4_virt_addr = page_table.level_4_table() as *const _ as u64;
-    let offset = page_table.phys_offset();
-    let pml4_phys_addr = VirtAddr::new(pml4_virt_addr).sub(offset);
-    let pml4_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(pml4_phys_addr));
+    let pml4_phys_addr = VirtAddr::new(pml4_virt_addr).sub(page_table.phys_offset());
+    PhysFrame::containing_address(PhysAddr::new(pml4_phys_addr))
+}
+
+pub fn switch_to_user_page_table(page_table: &mut OffsetPageTable) {
+    let pml4_frame = page_table_frame(page_table);
 
     unsafe {
         Cr3::write(pml4_frame, x86_64::registers::control::Cr3Flags::empty());
@@ -187,6 +429,179 @@ pub fn switch_to_user_page_table(page_table: &mut OffsetPageTable) {
     x86_64::instructions::tlb::flush_all();
 }
 
+// Unmap the lower (user) half of `page_table` and free the page table frames that backed it.
+// Leaf frames belong to the task (`Task::phys_pages`) and are left for the caller to release.
+pub fn free_user_page_tables(
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let offset = page_table.phys_offset();
+    let p4 = page_table.level_4_table_mut();
+
+    for p4_entry in p4.iter_mut().take(256) {
+        if !is_user_table_entry(p4_entry.flags()) {
+            continue;
+        }
+        let p3 = table_at(offset, p4_entry.addr());
+        for p3_entry in p3.iter_mut() {
+            if !is_user_table_entry(p3_entry.flags()) {
+                continue;
+            }
+            let p2 = table_at(offset, p3_entry.addr());
+            for p2_entry in p2.iter_mut() {
+                if !is_user_table_entry(p2_entry.flags()) {
+                    continue;
+                }
+                unsafe {
+                    frame_allocator
+                        .deallocate_frame(PhysFrame::containing_address(p2_entry.addr()));
+                }
+                p2_entry.set_unused();
+            }
+            unsafe {
+                frame_allocator.deallocate_frame(PhysFrame::containing_address(p3_entry.addr()));
+            }
+            p3_entry.set_unused();
+        }
+        unsafe {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(p4_entry.addr()));
+        }
+        p4_entry.set_unused();
+    }
+
+    x86_64::instructions::tlb::flush_all();
+}
+
+// Kernel entries are copied into every user PML4 without USER_ACCESSIBLE, so this is enough to
+// tell them apart from tables created for user mappings.
+fn is_user_table_entry(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+        && !flags.contains(PageTableFlags::HUGE_PAGE)
+}
+
+fn table_at(physical_memory_offset: VirtAddr, addr: PhysAddr) -> &'static mut PageTable {
+    let virt = physical_memory_offset + addr.as_u64();
+    unsafe { &mut *virt.as_mut_ptr::<PageTable>() }
+}
+
+// Software-defined PTE bit marking a page that is shared copy-on-write after fork.
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+// Frames mapped by more than one address space, with their reference count. Frames that are
+// not in the map have a single owner.
+static SHARED_FRAMES: SpinLock<BTreeMap<u64, u32>> = SpinLock::new(BTreeMap::new());
+
+pub fn frame_ref_count(frame: PhysFrame) -> u32 {
+    SHARED_FRAMES
+        .lock()
+        .get(&frame.start_address().as_u64())
+        .copied()
+        .unwrap_or(1)
+}
+
+pub fn share_frame(frame: PhysFrame) {
+    *SHARED_FRAMES
+        .lock()
+        .entry(frame.start_address().as_u64())
+        .or_insert(1) += 1;
+}
+
+// Drop one reference to `frame`, returning it to the allocator once nobody maps it anymore.
+pub fn release_frame(frame: PhysFrame, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    {
+        let mut shared = SHARED_FRAMES.lock();
+        let key = frame.start_address().as_u64();
+        if let Some(count) = shared.get_mut(&key) {
+            *count -= 1;
+            if *count <= 1 {
+                shared.remove(&key);
+            }
+            return;
+        }
+    }
+    unsafe {
+        frame_allocator.deallocate_frame(frame);
+    }
+}
+
+// Every present 4 KiB user mapping in `page_table` as (virtual address, leaf entry).
+pub fn user_leaf_entries(
+    page_table: &mut OffsetPageTable,
+) -> Vec<(VirtAddr, &'static mut PageTableEntry)> {
+    let offset = page_table.phys_offset();
+    let p4 = page_table.level_4_table_mut();
+    let mut entries = Vec::new();
+
+    for (i4, p4_entry) in p4.iter().enumerate().take(256) {
+        if !is_user_table_entry(p4_entry.flags()) {
+            continue;
+        }
+        let p3 = table_at(offset, p4_entry.addr());
+        for (i3, p3_entry) in p3.iter().enumerate() {
+            if !is_user_table_entry(p3_entry.flags()) {
+                continue;
+            }
+            let p2 = table_at(offset, p3_entry.addr());
+            for (i2, p2_entry) in p2.iter().enumerate() {
+                if !is_user_table_entry(p2_entry.flags()) {
+                    continue;
+                }
+                let p1 = table_at(offset, p2_entry.addr());
+                for (i1, p1_entry) in p1.iter_mut().enumerate() {
+                    if !p1_entry.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    let addr = ((i4 as u64) << 39)
+                        | ((i3 as u64) << 30)
+                        | ((i2 as u64) << 21)
+                        | ((i1 as u64) << 12);
+                    entries.push((VirtAddr::new(addr), p1_entry));
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+// Leaf entry mapping `addr` in `page_table`, if the walk down to it succeeds.
+pub fn user_leaf_entry(
+    page_table: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry> {
+    let offset = page_table.phys_offset();
+    let p4 = page_table.level_4_table_mut();
+
+    let p4_entry = &p4[addr.p4_index()];
+    if !is_user_table_entry(p4_entry.flags()) {
+        return None;
+    }
+    let p3_entry = &table_at(offset, p4_entry.addr())[addr.p3_index()];
+    if !is_user_table_entry(p3_entry.flags()) {
+        return None;
+    }
+    let p2_entry = &table_at(offset, p3_entry.addr())[addr.p2_index()];
+    if !is_user_table_entry(p2_entry.flags()) {
+        return None;
+    }
+    let p1_entry = &mut table_at(offset, p2_entry.addr())[addr.p1_index()];
+    if !p1_entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    Some(p1_entry)
+}
+
+pub fn switch_to_kernel_page_table() {
+    let pml4_frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_pml4()));
+    if get_current_page_table() == pml4_frame {
+        return;
+    }
+
+    unsafe {
+        Cr3::write(pml4_frame, x86_64::registers::control::Cr3Flags::empty());
+    }
+}
+
 pub fn get_current_page_table() -> PhysFrame<Size4KiB> {
     let (frame, _) = Cr3::read();
     frame
@@ -0,0 +1,246 @@
+use crate::errno::{Errno, KResult};
+use crate::fs::file::OpenFile;
+use crate::fs::vfs;
+use crate::memory;
+use crate::task::{self, Task};
+use crate::uaccess::USER_SPACE_END;
+use crate::vma::{Vma, VmaKind, PROT_EXEC, PROT_READ, PROT_WRITE};
+use alloc::sync::Arc;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Size4KiB};
+use x86_64::VirtAddr;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+
+const PAGE_SIZE: u64 = 4096;
+// Mappings without MAP_FIXED are placed top-down in [MMAP_MIN_ADDR, MMAP_TOP), below the stack
+// area with a guard page in between.
+const MMAP_MIN_ADDR: u64 = 0x10000;
+const MMAP_TOP: u64 = memory::USER_STACK_TOP - memory::USER_STACK_MAX_SIZE - PAGE_SIZE;
+
+fn page_align_len(len: u64) -> KResult<u64> {
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    len.checked_add(PAGE_SIZE - 1)
+        .map(|len| len & !(PAGE_SIZE - 1))
+        .ok_or(Errno::ENOMEM)
+}
+
+fn check_prot(prot: u64) -> KResult<u32> {
+    if prot & !((PROT_READ | PROT_WRITE | PROT_EXEC) as u64) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    Ok(prot as u32)
+}
+
+// The open file behind a mapping of `fd`, or None for an anonymous one. The mapping keeps its own
+// reference, so it outlives the descriptor.
+pub fn mmap_file(flags: u64, prot: u64, fd: u64, offset: u64) -> KResult<Option<Arc<OpenFile>>> {
+    if flags & MAP_ANONYMOUS != 0 {
+        return Ok(None);
+    }
+    if !offset.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let file = task::with_current_task(|task| task.file_descriptors.get(&fd).cloned())
+        .ok_or(Errno::ESRCH)?
+        .ok_or(Errno::EBADF)?;
+
+    let vfs_guard = vfs::lock();
+    let open_file = file.get(&vfs_guard);
+    let mode = open_file.f_mode.0;
+    let shared = flags & (MAP_SHARED | MAP_PRIVATE) == MAP_SHARED;
+    if mode & vfs::FMODE_READ == 0
+        || (shared && prot & PROT_WRITE as u64 != 0 && mode & vfs::FMODE_WRITE == 0)
+    {
+        return Err(Errno::EACCES);
+    }
+    vfs::check_mappable(open_file)?;
+    drop(vfs_guard);
+    Ok(Some(file))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn do_mmap(
+    task: &mut Task,
+    addr: u64,
+    len: u64,
+    prot: u64,
+    flags: u64,
+    file: Option<Arc<OpenFile>>,
+    offset: u64,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> KResult<u64> {
+    let prot = check_prot(prot)?;
+    let len = page_align_len(len)?;
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let start = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(Errno::EINVAL);
+        }
+        let end = addr
+            .checked_add(len)
+            .filter(|end| *end <= USER_SPACE_END)
+            .ok_or(Errno::ENOMEM)?;
+        if task.vmas.overlaps(addr, end) {
+            if flags & MAP_FIXED_NOREPLACE != 0 {
+                return Err(Errno::EEXIST);
+            }
+            task.unmap_region(addr, end, frame_allocator);
+        }
+        addr
+    } else {
+        // A free, page-aligned hint is taken as is; anything else gets a fresh spot.
+        let hint = addr & !(PAGE_SIZE - 1);
+        let hint_free = hint >= MMAP_MIN_ADDR
+            && hint
+                .checked_add(len)
+                .is_some_and(|end| end <= MMAP_TOP && !task.vmas.overlaps(hint, end));
+        if hint_free {
+            hint
+        } else {
+            task.vmas
+                .find_free(len, MMAP_MIN_ADDR, MMAP_TOP)
+                .ok_or(Errno::ENOMEM)?
+        }
+    };
+
+    let kind = if file.is_some() {
+        VmaKind::File
+    } else {
+        VmaKind::Anonymous
+    };
+    let vma = Vma {
+        start,
+        end: start + len,
+        prot,
+        kind,
+        shared,
+        file,
+        file_offset: offset,
+    };
+    let page_flags = vma.page_flags();
+    task.vmas.insert(vma)?;
+
+    // File pages are read in on first touch, see `task::handle_demand_fault`. Private anonymous
+    // pages are too; shared ones are populated right away, so that a later fork hands the same
+    // frames to the child.
+    if kind == VmaKind::Anonymous && shared {
+        for page in (start..start + len).step_by(PAGE_SIZE as usize) {
+            if !task.map_zeroed_page(VirtAddr::new(page), page_flags, frame_allocator) {
+                task.unmap_region(start, start + len, frame_allocator);
+                return Err(Errno::ENOMEM);
+            }
+        }
+    }
+
+    Ok(start)
+}
+
+pub fn do_munmap(
+    task: &mut Task,
+    addr: u64,
+    len: u64,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> KResult<u64> {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let len = page_align_len(len)?;
+    let end = addr
+        .checked_add(len)
+        .filter(|end| *end <= USER_SPACE_END)
+        .ok_or(Errno::EINVAL)?;
+
+    task.unmap_region(addr, end, frame_allocator);
+    Ok(0)
+}
+
+fn page_align_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// Move the program break to `addr` and return the resulting break. Like Linux, a request that
+// cannot be satisfied leaves the break where it was and simply returns it.
+pub fn do_brk(
+    task: &mut Task,
+    addr: u64,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> u64 {
+    if addr < task.brk_start || addr > MMAP_TOP {
+        return task.brk;
+    }
+
+    let old_end = page_align_up(task.brk);
+    let new_end = page_align_up(addr);
+
+    if new_end > old_end {
+        if task.vmas.overlaps(old_end, new_end) {
+            return task.brk;
+        }
+        // New pages are zero-filled on first touch. Only the new range is added, so mprotect
+        // calls on the existing heap stay in effect; the top heap area grows in place if it
+        // still has the default protection.
+        let prot = PROT_READ | PROT_WRITE;
+        let top = (old_end > task.brk_start)
+            .then(|| task.vmas.find_mut(old_end - 1))
+            .flatten()
+            .filter(|vma| vma.kind == VmaKind::Heap && vma.prot == prot && vma.end == old_end);
+        if let Some(top) = top {
+            top.end = new_end;
+        } else {
+            let heap = Vma {
+                start: old_end,
+                end: new_end,
+                prot,
+                kind: VmaKind::Heap,
+                shared: false,
+                file: None,
+                file_offset: 0,
+            };
+            if task.vmas.insert(heap).is_err() {
+                return task.brk;
+            }
+        }
+    } else if new_end < old_end {
+        task.unmap_region(new_end, old_end, frame_allocator);
+    }
+
+    task.brk = addr;
+    task.brk
+}
+
+pub fn do_mprotect(task: &mut Task, addr: u64, len: u64, prot: u64) -> KResult<u64> {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let prot = check_prot(prot)?;
+    if len == 0 {
+        return Ok(0);
+    }
+    let len = page_align_len(len)?;
+    let end = addr
+        .checked_add(len)
+        .filter(|end| *end <= USER_SPACE_END)
+        .ok_or(Errno::ENOMEM)?;
+    if !task.vmas.covers(addr, end) {
+        return Err(Errno::ENOMEM);
+    }
+
+    task.vmas.split_at(addr);
+    task.vmas.split_at(end);
+    for vma in task.vmas.range_mut(addr, end) {
+        vma.prot = prot;
+    }
+    task.apply_vma_protection(addr, end);
+    Ok(0)
+}
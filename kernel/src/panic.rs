@@ -8,6 +8,10 @@ use crate::klog;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    // The panic may have hit while the serial port was locked; nobody is going to release it.
+    unsafe {
+        crate::logging::PORT.force_unlock();
+    }
     klog!(Fatal, "Kernel panic: {}", _info);
     loop {}
 }
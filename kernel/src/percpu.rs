@@ -0,0 +1,103 @@
+use crate::instructions::{wrmsr, GS_BASE, KERNEL_GS_BASE};
+use crate::scheduler;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
+
+pub const MAX_CPUS: usize = 16;
+
+// State private to one CPU, found through the GS base. Both GS_BASE and KERNEL_GS_BASE point at
+// it: user space has no way to set a GS base of its own (FSGSBASE is off and there is no
+// arch_prctl), so the swapgs pair on the syscall path leaves it in place.
+#[repr(C)]
+pub struct PerCpu {
+    // Scratch slot for the user stack pointer and the stack to switch to, used by
+    // `syscall_handler` before it has a stack.
+    pub user_rsp: u64,
+    pub kernel_rsp: u64,
+    // Address of this struct, so `this_cpu` is a single gs-relative load.
+    this: *mut PerCpu,
+    pub cpu_id: u32,
+    pub apic_id: u32,
+    pub current_pid: u64,
+    // Saved stack pointer of this CPU's idle context (its boot thread).
+    pub idle_rsp: u64,
+    // Task switched away from last; `scheduler::finish_switch` lets go of it.
+    pub prev_pid: u64,
+    pub slice_remaining: u64,
+    // Monotonic time at which the current task was switched in, for CPU time accounting.
+    pub switched_in_ns: u64,
+    pub tss: TaskStateSegment,
+    pub gdt: GlobalDescriptorTable,
+}
+
+pub const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+pub const KERNEL_RSP_OFFSET: usize = offset_of!(PerCpu, kernel_rsp);
+const THIS_OFFSET: usize = offset_of!(PerCpu, this);
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            user_rsp: 0,
+            kernel_rsp: 0,
+            this: core::ptr::null_mut(),
+            cpu_id: 0,
+            apic_id: 0,
+            current_pid: 0,
+            idle_rsp: 0,
+            prev_pid: 0,
+            slice_remaining: 0,
+            switched_in_ns: 0,
+            tss: TaskStateSegment::new(),
+            gdt: GlobalDescriptorTable::new(),
+        }
+    }
+}
+
+struct CpuSlot(UnsafeCell<PerCpu>);
+
+// A slot is only written by the BSP before its CPU is started, and by that CPU afterwards.
+unsafe impl Sync for CpuSlot {}
+
+static CPUS: [CpuSlot; MAX_CPUS] = [const { CpuSlot(UnsafeCell::new(PerCpu::new())) }; MAX_CPUS];
+
+pub fn cpu(cpu_id: usize) -> &'static mut PerCpu {
+    unsafe { &mut *CPUS[cpu_id].0.get() }
+}
+
+// APIC ID of the calling CPU as reported by CPUID, usable before the local APIC is mapped.
+pub fn initial_apic_id() -> u32 {
+    __cpuid(1).ebx >> 24
+}
+
+// Claim the area of `cpu_id` for the calling CPU and point GS at it. Must run before anything
+// uses `this_cpu`, i.e. first thing on every CPU.
+pub fn init_this_cpu(cpu_id: usize) {
+    let cpu = cpu(cpu_id);
+    cpu.this = cpu as *mut PerCpu;
+    cpu.cpu_id = cpu_id as u32;
+    cpu.apic_id = initial_apic_id();
+    cpu.slice_remaining = scheduler::TIME_SLICE_TICKS;
+
+    let addr = cpu.this as u64;
+    unsafe {
+        wrmsr(GS_BASE, addr);
+        wrmsr(KERNEL_GS_BASE, addr);
+    }
+}
+
+pub fn this_cpu() -> &'static mut PerCpu {
+    let cpu: *mut PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{this}]",
+            out(reg) cpu,
+            this = const THIS_OFFSET,
+            options(nostack, preserves_flags, readonly),
+        );
+        &mut *cpu
+    }
+}
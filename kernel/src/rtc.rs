@@ -0,0 +1,136 @@
+use crate::acpi;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// Keeps NMIs disabled while a register is selected.
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    // Seconds since the Unix epoch; the RTC is assumed to run in UTC.
+    pub fn unix_seconds(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+// Days from 1970-01-01 to the given date (proleptic Gregorian calendar).
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = month as u64;
+    let day_of_year =
+        (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146097 + day_of_era).saturating_sub(719468)
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        let mut address = Port::new(CMOS_ADDRESS);
+        address.write(NMI_DISABLE | reg);
+        let value = Port::new(CMOS_DATA).read();
+        // Selecting the register again without the bit turns NMIs back on.
+        address.write(reg);
+        value
+    }
+}
+
+fn read_raw(century_reg: u8) -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        if century_reg != 0 {
+            read_register(century_reg)
+        } else {
+            0
+        },
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+// Read the current date and time. An update can still slip in between the status check and
+// the reads, so the registers are read until two passes agree.
+pub fn read() -> DateTime {
+    let century_reg = acpi::info()
+        .and_then(|acpi| acpi.fadt)
+        .map_or(0, |fadt| fadt.century);
+
+    let mut raw = read_raw(century_reg);
+    loop {
+        let again = read_raw(century_reg);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+
+    let status_b = read_register(REG_STATUS_B);
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        hour = from_bcd(hour);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+        century = from_bcd(century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is 0:xx, 12 PM stays 12:xx.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // Without a century register, assume the 2000s.
+    let century = if century != 0 { century as u32 } else { 20 };
+    DateTime {
+        year: century * 100 + year as u32,
+        month: month as u32,
+        day: day as u32,
+        hour: hour as u32,
+        minute: minute as u32,
+        second: second as u32,
+    }
+}
@@ -0,0 +1,270 @@
+use crate::gdt;
+use crate::interrupt_idx::RESCHEDULE_VECTOR;
+use crate::lapic;
+use crate::memory::{switch_to_kernel_page_table, switch_to_user_page_table};
+use crate::percpu;
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::syscall;
+use crate::task::{set_current_pid, with_current_task, with_task, TaskState, TrapFrame};
+use crate::time;
+use alloc::collections::VecDeque;
+use core::arch::naked_asm;
+
+// The interrupt entry stubs push exactly the general purpose registers on top of the CPU's frame.
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 20 * 8);
+
+// Number of timer ticks a task may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+// pid 0 is the idle context of each CPU: its boot thread parked in `run`. It never sits in the
+// run queue.
+pub const IDLE_PID: u64 = 0;
+
+// Shared by all CPUs. The lock also orders task state changes against wakeups, see
+// `set_current_state`.
+static RUN_QUEUE: SpinLock<VecDeque<u64>> = SpinLock::new(VecDeque::new());
+
+pub fn add_task(pid: u64) {
+    let mut queue = RUN_QUEUE.lock();
+    let on_cpu = with_task(pid, |task| {
+        task.state = TaskState::Runnable;
+        task.on_cpu
+    });
+    if on_cpu == Some(false) {
+        queue.push_back(pid);
+    }
+    drop(queue);
+    kick_idle_cpu();
+}
+
+// Make a blocked task runnable again. Tasks in any other state are left alone, so spurious
+// wakeups are harmless. A task still switching away on another CPU is queued by that CPU in
+// `finish_switch` instead.
+pub fn wake(pid: u64) {
+    let mut queue = RUN_QUEUE.lock();
+    let woken = with_task(pid, |task| {
+        if task.state != TaskState::Blocked {
+            return false;
+        }
+        task.state = TaskState::Runnable;
+        !task.on_cpu
+    });
+    if woken == Some(true) {
+        queue.push_back(pid);
+    }
+    drop(queue);
+    kick_idle_cpu();
+}
+
+// Get an idle CPU to look at the run queue now rather than on its next timer tick.
+fn kick_idle_cpu() {
+    let this_cpu = percpu::this_cpu().cpu_id as usize;
+    for cpu_id in (0..smp::online_cpus()).filter(|id| *id != this_cpu) {
+        let cpu = percpu::cpu(cpu_id);
+        if unsafe { core::ptr::read_volatile(&cpu.current_pid) } == IDLE_PID {
+            lapic::send_ipi_to(cpu.apic_id, RESCHEDULE_VECTOR);
+            return;
+        }
+    }
+}
+
+// Sent by `kick_idle_cpu`.
+pub fn reschedule_interrupt(_frame: &mut TrapFrame) {
+    if percpu::this_cpu().current_pid == IDLE_PID {
+        schedule();
+    }
+}
+
+// Change the state of the current task, e.g. to Blocked before checking the condition to sleep
+// on. Going through the queue lock means a `wake` on another CPU either sees the new state or
+// happened before it, so the wakeup can't get lost.
+pub fn set_current_state(state: TaskState) {
+    let _queue = RUN_QUEUE.lock();
+    with_current_task(|task| task.state = state);
+}
+
+// Spin until `pid` has fully left its CPU, so its kernel stack can be freed.
+pub fn wait_off_cpu(pid: u64) {
+    loop {
+        {
+            let _queue = RUN_QUEUE.lock();
+            if with_task(pid, |task| task.on_cpu) != Some(true) {
+                return;
+            }
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Called from the timer interrupt with interrupts disabled.
+pub fn tick() {
+    let cpu = percpu::this_cpu();
+    if cpu.slice_remaining > 0 {
+        cpu.slice_remaining -= 1;
+    }
+
+    if cpu.slice_remaining == 0 || cpu.current_pid == IDLE_PID {
+        schedule();
+    }
+}
+
+// Pick the next runnable task and switch to it. The current task goes back to the tail of the
+// run queue if it is still runnable, but only once `finish_switch` has run on the other side:
+// until its registers are saved no other CPU may pick it up. A task that blocked is simply
+// left out.
+// Must be called with interrupts disabled.
+pub fn schedule() {
+    let cpu = percpu::this_cpu();
+    let prev_pid = cpu.current_pid;
+    let mut queue = RUN_QUEUE.lock();
+
+    // The boot thread borrows a task's identity while loading it (see `jump_userspace`). It is
+    // not running on that task's stack, so there is nothing to switch away from.
+    if with_task(prev_pid, |task| task.on_cpu) == Some(false) {
+        return;
+    }
+
+    let prev_runnable = with_task(prev_pid, |task| {
+        if task.state == TaskState::Running {
+            task.state = TaskState::Runnable;
+        }
+        task.state == TaskState::Runnable
+    })
+    .unwrap_or(false);
+
+    let mut next_pid = IDLE_PID;
+    while let Some(pid) = queue.pop_front() {
+        if with_task(pid, |task| {
+            task.state == TaskState::Runnable && !task.on_cpu
+        }) == Some(true)
+        {
+            next_pid = pid;
+            break;
+        }
+    }
+    if next_pid == IDLE_PID && prev_runnable {
+        next_pid = prev_pid;
+    }
+
+    cpu.slice_remaining = TIME_SLICE_TICKS;
+
+    if next_pid == prev_pid {
+        with_task(prev_pid, |task| task.state = TaskState::Running);
+        return;
+    }
+
+    // A task is not freed while it is on a CPU, so its slot outlives the lock until the switch.
+    let now = time::monotonic_ns();
+    let switched_in_ns = cpu.switched_in_ns;
+    let prev_rsp: *mut u64 = with_task(prev_pid, |task| {
+        task.cpu_time_ns += now.saturating_sub(switched_in_ns);
+        &mut task.saved_rsp as *mut u64
+    })
+    .unwrap_or(&mut cpu.idle_rsp);
+    cpu.switched_in_ns = now;
+
+    let next_rsp = with_task(next_pid, |task| {
+        task.state = TaskState::Running;
+        task.on_cpu = true;
+        let stack_top = task.kernel_stack_top();
+        gdt::set_kernel_stack(stack_top);
+        syscall::set_kernel_stack(stack_top);
+        switch_to_user_page_table(&mut task.page_table);
+        task.saved_rsp
+    })
+    .unwrap_or_else(|| {
+        switch_to_kernel_page_table();
+        cpu.idle_rsp
+    });
+
+    drop(queue);
+    cpu.prev_pid = prev_pid;
+    set_current_pid(next_pid);
+    unsafe {
+        switch_context(prev_rsp, next_rsp);
+    }
+    finish_switch();
+}
+
+// First thing on the new side of a switch: the previous task's registers are saved now, so
+// other CPUs may run it again.
+extern "C" fn finish_switch() {
+    let cpu = percpu::this_cpu();
+    let prev_pid = core::mem::replace(&mut cpu.prev_pid, IDLE_PID);
+    let mut queue = RUN_QUEUE.lock();
+    let runnable = with_task(prev_pid, |task| {
+        task.on_cpu = false;
+        task.state == TaskState::Runnable
+    });
+    if runnable == Some(true) {
+        queue.push_back(prev_pid);
+    }
+}
+
+// CPU time used by `pid` so far, including the current stint if it is running on this CPU.
+pub fn cpu_time_ns(pid: u64) -> u64 {
+    let cpu = percpu::this_cpu();
+    let _queue = RUN_QUEUE.lock();
+    with_task(pid, |task| {
+        if cpu.current_pid == pid {
+            task.cpu_time_ns + time::monotonic_ns().saturating_sub(cpu.switched_in_ns)
+        } else {
+            task.cpu_time_ns
+        }
+    })
+    .unwrap_or(0)
+}
+
+// Park the calling CPU's boot thread as its idle context. Timer ticks switch away to whatever
+// has been queued with `add_task`.
+pub fn run() -> ! {
+    set_current_pid(IDLE_PID);
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn switch_context(prev_rsp: *mut u64, next_rsp: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+// Where a task's first switch lands, see `Task::prepare_user_entry`. The trap frame is right
+// above, and rsp is 16-byte aligned.
+#[unsafe(naked)]
+pub unsafe extern "C" fn task_entry() {
+    naked_asm!(
+        "call {finish}",
+        "jmp {ret}",
+
+        finish = sym finish_switch,
+        ret = sym interrupt_return,
+    );
+}
+
+// Pops a `TrapFrame` off the current stack and returns from the interrupt.
+#[unsafe(naked)]
+pub unsafe extern "C" fn interrupt_return() {
+    naked_asm!(
+        // Reverse order of the pushes in the entry stubs.
+        "pop r15", "pop r14", "pop r13", "pop r12", "pop r11", "pop r10", "pop r9", "pop r8",
+        "pop rbp", "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rbx", "pop rax", "iretq",
+    );
+}
@@ -0,0 +1,346 @@
+use crate::acpi;
+use crate::gdt;
+use crate::idt;
+use crate::instructions::{rdmsr, EFER};
+use crate::interrupt_idx::LAPIC_TIMER_VECTOR;
+use crate::klog;
+use crate::lapic;
+use crate::memory::{self, PHYSICAL_MEMORY_OFFSET};
+use crate::percpu::{self, MAX_CPUS};
+use crate::scheduler;
+use crate::sync::OnceCell;
+use crate::syscall;
+use alloc::vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::structures::paging::page_table::PageTable;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Application processors start in real mode at a page below 1 MiB given in the STARTUP IPI. The
+// trampoline is copied there; it switches straight to long mode on a temporary page table and
+// calls `ap_main` on a stack the BSP prepared. The lock makes the APs go through one at a time,
+// since they all share the stack slot and the cpu id handed out in AP_CPU_ID.
+global_asm!(
+    r#"
+    .pushsection .rodata.ap_trampoline, "a"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_trampoline_lock
+    .global ap_trampoline_gdtr
+    .global ap_trampoline_gdt
+    .global ap_trampoline_far_jump
+    .global ap_trampoline_long_mode
+    .global ap_trampoline_cr3
+    .global ap_trampoline_efer
+    .global ap_trampoline_stack
+    .global ap_trampoline_entry
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+2:
+    lock btsl $0, (ap_trampoline_lock - ap_trampoline_start)
+    jnc 3f
+    pause
+    jmp 2b
+3:
+    lgdtl (ap_trampoline_gdtr - ap_trampoline_start)
+
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start), %eax
+    mov %eax, %cr3
+    mov $0xC0000080, %ecx
+    movl (ap_trampoline_efer - ap_trampoline_start), %eax
+    xor %edx, %edx
+    wrmsr
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+    ljmpl *(ap_trampoline_far_jump - ap_trampoline_start)
+
+    .code64
+ap_trampoline_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov ap_trampoline_stack(%rip), %rsp
+    mov ap_trampoline_entry(%rip), %rax
+    call *%rax
+    ud2
+
+    .balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+ap_trampoline_gdtr:
+    .word 3 * 8 - 1
+    .long 0
+ap_trampoline_far_jump:
+    .long 0
+    .word 0x08
+    .balign 4
+ap_trampoline_lock:
+    .long 1
+ap_trampoline_cr3:
+    .long 0
+ap_trampoline_efer:
+    .long 0
+    .balign 8
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_end:
+    .popsection
+"#,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_lock: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_far_jump: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+}
+
+const AP_STACK_SIZE: usize = 4096 * 4;
+const EFER_LMA: u64 = 1 << 10;
+
+static TRAMPOLINE: OnceCell<PhysFrame> = OnceCell::new();
+
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+// Handed to the AP currently holding the trampoline lock.
+static AP_CPU_ID: AtomicUsize = AtomicUsize::new(0);
+static AP_DOUBLE_FAULT_STACK: AtomicU64 = AtomicU64::new(0);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+// Control registers of the BSP, for the APs to copy.
+static BSP_CR0: AtomicU64 = AtomicU64::new(0);
+static BSP_CR4: AtomicU64 = AtomicU64::new(0);
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+// Set aside a page below 1 MiB for the trampoline. Has to happen before the low frames are
+// handed out for anything else.
+pub fn reserve_trampoline() {
+    let mut frame_allocator = memory::frame_allocator();
+    match frame_allocator.allocate_contiguous(1, 1) {
+        Some(frame) if frame.start_address().as_u64() < 0x10_0000 => {
+            let _ = TRAMPOLINE.set(frame);
+        }
+        Some(frame) => unsafe {
+            frame_allocator.deallocate_contiguous(frame, 1);
+        },
+        None => {}
+    }
+}
+
+fn trampoline_offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - (&raw const ap_trampoline_start) as u64
+}
+
+// Page table the APs enable paging with. CR3 is loaded in 32-bit form, so it has to sit below
+// 4 GiB. It has the kernel half of the kernel page table plus an identity mapping of the
+// trampoline page, which the AP executes from while paging comes on.
+fn build_trampoline_page_table(trampoline: PhysFrame) -> Option<PhysFrame> {
+    let mut frame_allocator = memory::frame_allocator();
+    let pml4 = frame_allocator.allocate_contiguous(1, 1)?;
+    if pml4.start_address().as_u64() >= 0x1_0000_0000 {
+        unsafe {
+            frame_allocator.deallocate_contiguous(pml4, 1);
+        }
+        return None;
+    }
+
+    let kernel_p4 =
+        unsafe { &*((PHYSICAL_MEMORY_OFFSET + memory::kernel_pml4()) as *const PageTable) };
+    let new_p4 = unsafe {
+        &mut *((PHYSICAL_MEMORY_OFFSET + pml4.start_address().as_u64()) as *mut PageTable)
+    };
+    new_p4.zero();
+    for i in 1..512 {
+        new_p4[i] = kernel_p4[i].clone();
+    }
+
+    let mut mapper =
+        memory::page_table_frame_to_mapper(pml4, VirtAddr::new(PHYSICAL_MEMORY_OFFSET));
+    let page =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+    unsafe {
+        mapper
+            .map_to(
+                page,
+                trampoline,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut frame_allocator,
+            )
+            .ok()?
+            .ignore();
+    }
+    Some(pml4)
+}
+
+fn trampoline_field<T>(trampoline: PhysFrame, symbol: &u8) -> *mut T {
+    (PHYSICAL_MEMORY_OFFSET + trampoline.start_address().as_u64() + trampoline_offset(symbol))
+        as *mut T
+}
+
+// Give the next AP a stack and a cpu id, then let it through the trampoline lock. Returns once
+// it reports in, or false if none showed up in time.
+fn start_next_ap(trampoline: PhysFrame, cpu_id: usize, timeout_us: u64) -> bool {
+    // Leaked: the stacks live as long as their CPU.
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let double_fault_stack = vec![0u8; gdt::DOUBLE_FAULT_STACK_SIZE].leak();
+
+    AP_CPU_ID.store(cpu_id, Ordering::Relaxed);
+    AP_DOUBLE_FAULT_STACK.store(
+        double_fault_stack.as_ptr() as u64 + gdt::DOUBLE_FAULT_STACK_SIZE as u64,
+        Ordering::Relaxed,
+    );
+    AP_STARTED.store(false, Ordering::Relaxed);
+    unsafe {
+        let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+        core::ptr::write_volatile(
+            trampoline_field::<u64>(trampoline, &ap_trampoline_stack),
+            stack_top,
+        );
+        // Release: everything above must be visible before the AP can take the lock.
+        AtomicU32::from_ptr(trampoline_field(trampoline, &ap_trampoline_lock))
+            .store(0, Ordering::Release);
+    }
+
+    let mut waited = 0;
+    while !AP_STARTED.load(Ordering::Acquire) {
+        if waited >= timeout_us {
+            return false;
+        }
+        lapic::delay_us(100);
+        waited += 100;
+    }
+    true
+}
+
+// Bring up the other CPUs with INIT-SIPI-SIPI. Runs on the BSP once the heap, its local APIC and
+// the PIT are up, and before any task exists.
+pub fn init() {
+    lapic::calibrate_timer();
+
+    let Some(&trampoline) = TRAMPOLINE.get() else {
+        klog!(Warn, "No free page below 1 MiB, not starting other CPUs.");
+        return;
+    };
+    // Slot 0 of the trampoline page table is the identity mapping.
+    if (ap_main as *const () as u64 >> 39) & 0x1FF == 0 {
+        klog!(
+            Warn,
+            "Kernel is mapped in the lower half, not starting other CPUs."
+        );
+        return;
+    }
+    let Some(page_table) = build_trampoline_page_table(trampoline) else {
+        klog!(
+            Warn,
+            "Failed to build the AP page table, not starting other CPUs."
+        );
+        return;
+    };
+
+    let base = trampoline.start_address().as_u64();
+    unsafe {
+        let start = &raw const ap_trampoline_start;
+        let len = (&raw const ap_trampoline_end) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, (PHYSICAL_MEMORY_OFFSET + base) as *mut u8, len);
+
+        *trampoline_field::<u32>(trampoline, &ap_trampoline_gdtr).byte_add(2) =
+            (base + trampoline_offset(&ap_trampoline_gdt)) as u32;
+        *trampoline_field::<u32>(trampoline, &ap_trampoline_far_jump) =
+            (base + trampoline_offset(&ap_trampoline_long_mode)) as u32;
+        *trampoline_field::<u32>(trampoline, &ap_trampoline_cr3) =
+            page_table.start_address().as_u64() as u32;
+        *trampoline_field::<u32>(trampoline, &ap_trampoline_efer) =
+            (rdmsr(EFER) & !EFER_LMA) as u32;
+        *trampoline_field::<u64>(trampoline, &ap_trampoline_entry) = ap_main as *const () as u64;
+    }
+    BSP_CR0.store(Cr0::read_raw(), Ordering::Relaxed);
+    BSP_CR4.store(Cr4::read_raw(), Ordering::Relaxed);
+
+    lapic::broadcast_init();
+    lapic::delay_us(10_000);
+    let page = (base >> 12) as u8;
+    lapic::broadcast_startup(page);
+    lapic::delay_us(200);
+    lapic::broadcast_startup(page);
+
+    // Without a MADT there is no telling how many CPUs there are; keep going until one doesn't
+    // show up.
+    let cpu_count = acpi::info()
+        .map(|acpi| acpi.enabled_cpus().count())
+        .filter(|count| *count > 0)
+        .unwrap_or(MAX_CPUS);
+    if cpu_count > MAX_CPUS {
+        klog!(Warn, "Only starting {} of {} CPUs.", MAX_CPUS, cpu_count);
+    }
+
+    // The first AP also has to get through its startup; the others are already spinning on
+    // the lock by then.
+    for cpu_id in 1..cpu_count.min(MAX_CPUS) {
+        let timeout_us = if cpu_id == 1 { 100_000 } else { 10_000 };
+        if !start_next_ap(trampoline, cpu_id, timeout_us) {
+            break;
+        }
+    }
+
+    klog!(Info, "{} CPU(s) online.", online_cpus());
+}
+
+extern "C" fn ap_main() -> ! {
+    unsafe {
+        Cr3::write(
+            PhysFrame::containing_address(PhysAddr::new(memory::kernel_pml4())),
+            Cr3Flags::empty(),
+        );
+        Cr0::write(Cr0Flags::from_bits_truncate(
+            BSP_CR0.load(Ordering::Relaxed),
+        ));
+        Cr4::write(Cr4Flags::from_bits_truncate(
+            BSP_CR4.load(Ordering::Relaxed),
+        ));
+    }
+
+    let cpu_id = AP_CPU_ID.load(Ordering::Relaxed);
+    percpu::init_this_cpu(cpu_id);
+    gdt::init_tss_with_stack(VirtAddr::new(AP_DOUBLE_FAULT_STACK.load(Ordering::Relaxed)));
+    gdt::init_gdt();
+    idt::init_idt();
+    syscall::configure_syscalls();
+    lapic::init();
+    lapic::start_periodic_timer(LAPIC_TIMER_VECTOR);
+
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    klog!(
+        Debug,
+        "CPU {} online, APIC ID {}.",
+        cpu_id,
+        percpu::this_cpu().apic_id
+    );
+    AP_STARTED.store(true, Ordering::Release);
+
+    scheduler::run();
+}
@@ -0,0 +1,280 @@
+use crate::scheduler;
+use crate::task::{self, TaskState};
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+
+// Busy-waiting lock for data that is also touched from interrupt handlers. Interrupts stay
+// disabled while the guard is alive, so a handler can never spin on a lock its own CPU holds.
+// The guard puts IF back the way it found it.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irq_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        SpinLockGuard {
+            lock: self,
+            irq_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return Some(SpinLockGuard {
+                lock: self,
+                irq_enabled,
+            });
+        }
+        if irq_enabled {
+            interrupts::enable();
+        }
+        None
+    }
+
+    // Release the lock without a guard. Only for the panic path, where whoever held it is never
+    // coming back.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.irq_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    // Pids of the tasks sleeping on the mutex, woken in FIFO order.
+    waiters: VecDeque<u64>,
+}
+
+// Sleeping lock for data that is only used from task context and may be held for a long time
+// (file contents, anything that copies to or from user space). A task that finds it taken blocks
+// and lets the scheduler run something else. Before there is a task to block (boot, the idle
+// context) it falls back to spinning. Never take it from an interrupt handler or with a
+// SpinLock held.
+pub struct Mutex<T> {
+    state: SpinLock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: SpinLock::new(MutexState {
+                locked: false,
+                waiters: VecDeque::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // `schedule` wants interrupts off, and a wakeup must not slip in between queueing
+        // ourselves and going to sleep.
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        loop {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                break;
+            }
+
+            match task::with_current_task(|current| current.pid) {
+                Some(pid) => {
+                    if !state.waiters.contains(&pid) {
+                        state.waiters.push_back(pid);
+                    }
+                    scheduler::set_current_state(TaskState::Blocked);
+                    drop(state);
+                    scheduler::schedule();
+                }
+                None => {
+                    drop(state);
+                    core::hint::spin_loop();
+                }
+            }
+        }
+
+        if irq_enabled {
+            interrupts::enable();
+        }
+        MutexGuard { mutex: self }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        // The woken task competes for the lock again, so a spurious wakeup costs a retry.
+        if let Some(pid) = state.waiters.pop_front() {
+            drop(state);
+            scheduler::wake(pid);
+        }
+    }
+}
+
+const ONCE_EMPTY: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_READY: u8 = 2;
+
+// Write-once cell for globals that are set up during boot and only read afterwards.
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell {
+            state: AtomicU8::new(ONCE_EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    // Store `value` unless the cell was already set, in which case it is handed back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_EMPTY,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe {
+            (*self.value.get()).write(value);
+        }
+        self.state.store(ONCE_READY, Ordering::Release);
+        Ok(())
+    }
+
+    // Return the value, running `init` to produce it first if the cell is still empty. Callers
+    // that lose the race wait for the winner to finish.
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_EMPTY,
+                ONCE_RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            unsafe {
+                (*self.value.get()).write(init());
+            }
+            self.state.store(ONCE_READY, Ordering::Release);
+        }
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == ONCE_READY {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
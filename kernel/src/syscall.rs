@@ -1,68 +1,67 @@
-use crate::fs::vfs;
-use crate::gdt::SELECTORS;
-use crate::instructions::{rdmsr, wrmsr, EFER, FMASK, KERNEL_GS_BASE, LSTAR, STAR};
+use crate::errno::{Errno, KResult};
+use crate::fs::dentry::Dentry;
+use crate::fs::file::OpenFile;
+use crate::fs::inode::Inode;
+use crate::fs::namei;
+use crate::fs::stat;
+use crate::fs::vfs::{self, PATH_MAX};
+use crate::gdt;
+use crate::instructions::{rdmsr, wrmsr, EFER, FMASK, LSTAR, STAR};
 use crate::klog;
-use crate::task::{get_current_task, getpid, getppid, TrapFrame};
+use crate::memory;
+use crate::mmap;
+use crate::percpu;
+use crate::scheduler;
+use crate::task;
+use crate::task::{getpid, getppid, with_current_task, TaskState, TrapFrame};
+use crate::time;
 use crate::types::FMode;
+use crate::uaccess;
+use crate::userspace;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::naked_asm;
 
-#[repr(align(16))]
-struct KernelStack([u8; 16384]);
-
-#[repr(C, align(16))]
-struct PerCpu {
-    user_rsp: u64,
-    kernel_rsp: u64,
-    current_task: u64,
-    cpu_id: u32,
-}
-
-static mut KERNEL_STACK: KernelStack = KernelStack([0; 16384]);
-static mut PER_CPU_DATA: PerCpu = PerCpu {
-    user_rsp: 0,
-    kernel_rsp: 0,
-    current_task: 0,
-    cpu_id: 0,
-};
-
+// Program the syscall MSRs of the calling CPU. The GS base they rely on is set up by
+// `percpu::init_this_cpu`.
 pub fn configure_syscalls() {
-    #[allow(static_mut_refs)]
     unsafe {
         let syscall_handler_addr = syscall_handler as *const () as u64;
         let efer = rdmsr(EFER);
 
-        let syscall_cs_ss_base = (SELECTORS.kernel_code_selector.0 & 0xFFFC) as u32;
-        let sysret_cs_ss_base = ((SELECTORS.user_code_selector.0 & 0xFFFC) - 16) as u32;
+        let selectors = gdt::selectors();
+        let syscall_cs_ss_base = (selectors.kernel_code_selector.0 & 0xFFFC) as u32;
+        let sysret_cs_ss_base = ((selectors.user_code_selector.0 & 0xFFFC) - 16) as u32;
         let star_high = (syscall_cs_ss_base) | (sysret_cs_ss_base << 16);
 
-        let stack_top = KERNEL_STACK.0.as_ptr() as u64 + 16384;
-        PER_CPU_DATA.kernel_rsp = stack_top;
-        PER_CPU_DATA.user_rsp = 0;
-        PER_CPU_DATA.current_task = 0;
-        PER_CPU_DATA.cpu_id = 0;
-
-        let per_cpu_addr = &raw mut PER_CPU_DATA as *mut _ as u64;
-
         wrmsr(STAR, (star_high as u64) << 32);
         wrmsr(LSTAR, syscall_handler_addr);
         wrmsr(FMASK, 0x0300);
         wrmsr(EFER, efer | 0x1);
-        wrmsr(KERNEL_GS_BASE, per_cpu_addr);
     }
 }
 
+// Stack `syscall_handler` switches to; follows the running task's kernel stack.
+pub fn set_kernel_stack(stack_top: u64) {
+    percpu::this_cpu().kernel_rsp = stack_top;
+}
+
 #[unsafe(naked)]
 #[no_mangle]
 pub unsafe extern "C" fn syscall_handler() {
     naked_asm!(
         "swapgs",
-        "mov gs:[0], rsp",
-        "mov rsp, gs:[8]",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
 
-        "push 0x1b",
-        "push gs:[0]",
-        "push r11",
+        // Build an iret frame (ss, rsp, rflags, cs, rip) so the saved state is a complete
+        // TrapFrame; 0x23/0x2b are the user data/code selectors set up in gdt::init_gdt.
         "push 0x23",
+        "push gs:[{user_rsp}]",
+        "push r11",
+        "push 0x2b",
         "push rcx",
 
         "push rax",
@@ -107,160 +106,463 @@ pub unsafe extern "C" fn syscall_handler() {
         "swapgs",
         "sysretq",
 
-         handler = sym syscall_dispatch,
+        handler = sym syscall_dispatch,
+        user_rsp = const percpu::USER_RSP_OFFSET,
+        kernel_rsp = const percpu::KERNEL_RSP_OFFSET,
     );
 }
 
 #[no_mangle]
 extern "C" fn syscall_dispatch(frame: &mut TrapFrame) -> u64 {
-    let task = get_current_task().expect("Failed to get current task");
-
-    task.trap_frame = frame as *mut TrapFrame;
+    let trap_frame = frame as *mut TrapFrame;
+    with_current_task(|task| task.trap_frame = trap_frame).expect("Failed to get current task");
 
-    let result = match frame.rax {
+    let result: KResult<u64> = match frame.rax {
         1 => sys_write(frame.rdi, frame.rsi, frame.rdx),
         2 => sys_open(frame.rdi, frame.rsi, frame.rdx),
         0 => sys_read(frame.rdi, frame.rsi, frame.rdx),
         3 => sys_close(frame.rdi),
+        4 => sys_stat(frame.rdi, frame.rsi, namei::LOOKUP_FOLLOW),
+        5 => sys_fstat(frame.rdi, frame.rsi),
+        6 => sys_stat(frame.rdi, frame.rsi, 0),
+        9 => sys_mmap(
+            frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+        ),
+        10 => sys_mprotect(frame.rdi, frame.rsi, frame.rdx),
+        11 => sys_munmap(frame.rdi, frame.rsi),
+        12 => sys_brk(frame.rdi),
         39 => sys_getpid(),
+        96 => sys_gettimeofday(frame.rdi, frame.rsi),
+        57 => sys_fork(frame),
+        59 => sys_execve(frame),
         60 => sys_exit(frame.rdi),
+        61 => sys_wait4(frame.rdi, frame.rsi, frame.rdx, frame.r10),
+        79 => sys_getcwd(frame.rdi, frame.rsi),
+        80 => sys_chdir(frame.rdi),
+        81 => sys_fchdir(frame.rdi),
         110 => sys_getppid(),
-        _ => u64::MAX,
+        165 => sys_mount(frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8),
+        166 => sys_umount2(frame.rdi, frame.rsi),
+        201 => sys_time(frame.rdi),
+        228 => sys_clock_gettime(frame.rdi, frame.rsi),
+        231 => sys_exit(frame.rdi),
+        332 => sys_statx(frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8),
+        _ => Err(Errno::ENOSYS),
     };
 
+    let result = match result {
+        Ok(value) => value,
+        Err(err) => err.as_syscall_return(),
+    };
     frame.rax = result;
     result
 }
 
-fn sys_write(fd: u64, buf: u64, count: u64) -> u64 {
+// The open file behind `fd` in the calling task.
+fn current_file(fd: u64) -> KResult<Arc<OpenFile>> {
+    with_current_task(|task| task.file_descriptors.get(&fd).cloned())
+        .ok_or(Errno::ESRCH)?
+        .ok_or(Errno::EBADF)
+}
+
+// Syscall I/O goes through a kernel buffer of at most this many bytes at a time.
+const IO_CHUNK: usize = 4096;
+
+// Hand `count` bytes of user memory at `buf` to `sink` one chunk at a time. Stops at the first
+// short write; a bad pointer only fails the call if nothing was transferred yet, like Linux.
+fn write_from_user(
+    buf: u64,
+    count: usize,
+    mut sink: impl FnMut(&[u8]) -> KResult<usize>,
+) -> KResult<usize> {
+    let mut chunk = vec![0u8; count.min(IO_CHUNK)];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(IO_CHUNK);
+        let step = uaccess::copy_from_user(&mut chunk[..len], buf + done as u64)
+            .and_then(|_| sink(&chunk[..len]));
+        match step {
+            Ok(written) => {
+                done += written;
+                if written < len {
+                    break;
+                }
+            }
+            Err(err) if done == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+// The other direction: fill chunks from `source` and copy them out to `buf`.
+fn read_to_user(
+    buf: u64,
+    count: usize,
+    mut source: impl FnMut(&mut [u8]) -> KResult<usize>,
+) -> KResult<usize> {
+    let mut chunk = vec![0u8; count.min(IO_CHUNK)];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(IO_CHUNK);
+        let step = source(&mut chunk[..len]).and_then(|read| {
+            uaccess::copy_to_user(buf + done as u64, &chunk[..read])?;
+            Ok(read)
+        });
+        match step {
+            Ok(read) => {
+                done += read;
+                if read < len {
+                    break;
+                }
+            }
+            Err(err) if done == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+fn sys_write(fd: u64, buf: u64, count: u64) -> KResult<u64> {
+    let count = count as usize;
+
     // Handle stdout/stderr (fd 1, 2) - write to kernel log
     if fd == 1 || fd == 2 {
-        let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, count as usize) };
-        let msg = core::str::from_utf8(slice).unwrap_or("<invalid utf-8>");
+        let written = write_from_user(buf, count, |chunk| {
+            let msg = core::str::from_utf8(chunk).unwrap_or("<invalid utf-8>");
+            klog!(
+                Debug,
+                "sys_write called with fd={}, buf=\"{}\", count={}",
+                fd,
+                msg,
+                chunk.len()
+            );
+            Ok(chunk.len())
+        })?;
+        return Ok(written as u64);
+    }
+
+    // Handle file descriptors
+    let file = current_file(fd)?;
+
+    let mut preview = Vec::new();
+    let result = write_from_user(buf, count, |chunk| {
+        if preview.is_empty() {
+            preview.extend_from_slice(&chunk[..chunk.len().min(64)]);
+        }
+        let mut vfs_guard = vfs::lock();
+        vfs::write_file(file.get_mut(&mut vfs_guard), chunk)
+    })?;
+
+    // Log file write (but truncate long messages)
+    let preview = &preview[..result.min(preview.len())];
+    if let Ok(msg) = core::str::from_utf8(preview) {
+        if result > 64 {
+            klog!(
+                Debug,
+                "sys_write: wrote {} bytes to fd={} (preview: \"{}...\")",
+                result,
+                fd,
+                msg
+            );
+        } else {
+            klog!(
+                Debug,
+                "sys_write: wrote {} bytes to fd={} (content: \"{}\")",
+                result,
+                fd,
+                msg.trim_end_matches('\n')
+            );
+        }
+    } else {
         klog!(
             Debug,
-            "sys_write called with fd={}, buf=\"{}\", count={}",
-            fd,
-            msg,
-            count
+            "sys_write: wrote {} bytes to fd={} (binary data)",
+            result,
+            fd
         );
-        return count;
     }
+    Ok(result as u64)
+}
 
-    // Handle file descriptors
-    let task = match get_current_task() {
-        Some(t) => t,
-        None => return u64::MAX,
-    };
+const ARG_MAX: usize = 1024;
+// Longest single argv or envp string, 32 pages like Linux's MAX_ARG_STRLEN.
+const MAX_ARG_STRLEN: usize = 32 * 4096;
 
-    let file = match task.file_descriptors.get_mut(&fd) {
-        Some(f) => f,
-        None => return u64::MAX,
-    };
+// Copy a NUL-terminated string of at most `max_len` bytes out of user memory.
+fn read_user_c_string(ptr: u64, max_len: usize) -> KResult<String> {
+    uaccess::strncpy_from_user(ptr, max_len)
+}
 
-    let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, count as usize) };
-    let result = vfs::write_file(file.as_mut(), slice);
+// Copy a NULL-terminated array of string pointers (argv, envp) out of user memory.
+fn read_user_string_array(ptr: u64) -> KResult<Vec<String>> {
+    let mut strings = Vec::new();
+    if ptr == 0 {
+        return Ok(strings);
+    }
 
-    if result < 0 {
-        u64::MAX
-    } else {
-        // Log file write (but truncate long messages)
-        let preview_len = result.min(64) as usize;
-        let preview = &slice[..preview_len.min(slice.len())];
-        if let Ok(msg) = core::str::from_utf8(preview) {
-            if result as usize > 64 {
-                klog!(
-                    Debug,
-                    "sys_write: wrote {} bytes to fd={} (preview: \"{}...\")",
-                    result,
-                    fd,
-                    msg
-                );
-            } else {
-                klog!(
-                    Debug,
-                    "sys_write: wrote {} bytes to fd={} (content: \"{}\")",
-                    result,
-                    fd,
-                    msg.trim_end_matches('\n')
-                );
-            }
-        } else {
+    loop {
+        let entry: u64 = uaccess::get_user(ptr + strings.len() as u64 * 8)?;
+        if entry == 0 {
+            break;
+        }
+        if strings.len() >= ARG_MAX {
+            return Err(Errno::E2BIG);
+        }
+        let string = read_user_c_string(entry, MAX_ARG_STRLEN).map_err(|err| match err {
+            Errno::ENAMETOOLONG => Errno::E2BIG,
+            err => err,
+        })?;
+        strings.push(string);
+    }
+
+    Ok(strings)
+}
+
+fn sys_fork(frame: &mut TrapFrame) -> KResult<u64> {
+    match task::fork_current(frame, &mut memory::frame_allocator()) {
+        Ok(child_pid) => {
             klog!(
                 Debug,
-                "sys_write: wrote {} bytes to fd={} (binary data)",
-                result,
-                fd
+                "sys_fork: pid {} forked child {}",
+                getpid(),
+                child_pid
             );
+            scheduler::add_task(child_pid);
+            Ok(child_pid)
+        }
+        Err(err) => {
+            klog!(Debug, "sys_fork: {:?}", err);
+            Err(err)
         }
-        result as u64
     }
 }
 
-fn sys_getpid() -> u64 {
+fn sys_execve(frame: &mut TrapFrame) -> KResult<u64> {
+    let path = read_user_c_string(frame.rdi, PATH_MAX)?;
+    let argv = read_user_string_array(frame.rsi)?;
+    let envp = read_user_string_array(frame.rdx)?;
+
+    klog!(
+        Debug,
+        "sys_execve called with path=\"{}\", argc={}, envc={}",
+        path,
+        argv.len(),
+        envp.len()
+    );
+
+    let data = userspace::read_program(&path, &argv, &envp).inspect_err(|err| {
+        klog!(Debug, "sys_execve: {:?}", err);
+    })?;
+
+    match userspace::load_program(&path, &data, &argv, &envp, &mut memory::frame_allocator()) {
+        Ok((entry, user_rsp)) => {
+            // Start the new image with a clean register file; sysret picks up rip, rflags
+            // and rsp from the frame.
+            *frame = TrapFrame {
+                rip: entry,
+                cs: frame.cs,
+                rflags: 0x202,
+                rsp: user_rsp,
+                ss: frame.ss,
+                ..TrapFrame::default()
+            };
+            Ok(0)
+        }
+        // Past the point of no return: there is no image left to return to.
+        Err(err) => {
+            klog!(
+                Debug,
+                "sys_execve: {:?} after the old image was released",
+                err
+            );
+            task::exit_current(task::SIGSEGV);
+        }
+    }
+}
+
+// sys_mmap(addr, length, prot, flags, fd, offset)
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> KResult<u64> {
+    let result = mmap::mmap_file(flags, prot, fd, offset).and_then(|file| {
+        with_current_task(|task| {
+            mmap::do_mmap(
+                task,
+                addr,
+                len,
+                prot,
+                flags,
+                file,
+                offset,
+                &mut memory::frame_allocator(),
+            )
+        })
+        .unwrap_or(Err(Errno::ESRCH))
+    });
+    klog!(
+        Debug,
+        "sys_mmap(addr={:#x}, len={:#x}, prot={:#x}, flags={:#x}, fd={}, offset={:#x}) = {:x?}",
+        addr,
+        len,
+        prot,
+        flags,
+        fd as i64,
+        offset,
+        result
+    );
+    result
+}
+
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> KResult<u64> {
+    with_current_task(|task| mmap::do_mprotect(task, addr, len, prot)).unwrap_or(Err(Errno::ESRCH))
+}
+
+fn sys_munmap(addr: u64, len: u64) -> KResult<u64> {
+    with_current_task(|task| mmap::do_munmap(task, addr, len, &mut memory::frame_allocator()))
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+fn sys_brk(addr: u64) -> KResult<u64> {
+    let brk = with_current_task(|task| mmap::do_brk(task, addr, &mut memory::frame_allocator()))
+        .ok_or(Errno::ESRCH)?;
+    klog!(Debug, "sys_brk({:#x}) = {:#x}", addr, brk);
+    Ok(brk)
+}
+
+fn sys_getpid() -> KResult<u64> {
     let pid = getpid();
     klog!(Debug, "sys_getpid called, returning pid={}", pid);
-    pid
+    Ok(pid)
 }
 
-fn sys_getppid() -> u64 {
+fn sys_getppid() -> KResult<u64> {
     let ppid = getppid();
     klog!(Debug, "sys_getppid called, returning ppid={}", ppid);
-    ppid
+    Ok(ppid)
 }
 
-fn sys_exit(code: u64) -> u64 {
-    klog!(Debug, "Process exited with code {}", code);
-    42
+fn sys_exit(code: u64) -> KResult<u64> {
+    klog!(Debug, "Process {} exited with code {}", getpid(), code);
+    task::exit_current(((code & 0xFF) as u32) << 8);
 }
 
-// Read from a file descriptor
-// sys_read(fd, buf, count)
-fn sys_read(fd: u64, buf: u64, count: u64) -> u64 {
-    let task = match get_current_task() {
-        Some(t) => t,
-        None => return u64::MAX,
-    };
+const WNOHANG: u64 = 1;
+
+// sys_wait4(pid, wstatus, options, rusage)
+// pid == -1 (or any value <= 0, there are no process groups) waits for any child.
+fn sys_wait4(pid: u64, wstatus: u64, options: u64, _rusage: u64) -> KResult<u64> {
+    let wanted = pid as i64;
+
+    loop {
+        // Mark ourselves asleep before looking, so that a child exiting on another CPU in the
+        // meantime still finds something to wake.
+        scheduler::set_current_state(TaskState::Blocked);
+        let reaped = reap_child(wanted, wstatus);
+        if reaped.is_some() || options & WNOHANG != 0 {
+            scheduler::set_current_state(TaskState::Running);
+            return reaped.unwrap_or(Ok(0));
+        }
 
-    let file = match task.file_descriptors.get_mut(&fd) {
-        Some(f) => f,
-        None => return u64::MAX,
-    };
+        // Sleep until a child exits; exit_current wakes the parent.
+        scheduler::schedule();
+    }
+}
 
-    let buffer = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count as usize) };
-    let result = vfs::read_file(file.as_mut(), buffer);
+// One pass of wait4: reap a zombie child matching `wanted`, or None if there is none yet.
+fn reap_child(wanted: i64, wstatus: u64) -> Option<KResult<u64>> {
+    let children: Vec<u64> = task::children_of(getpid())
+        .into_iter()
+        .filter(|child| wanted <= 0 || *child == wanted as u64)
+        .collect();
 
-    if result < 0 {
-        u64::MAX
-    } else {
-        result as u64
+    if children.is_empty() {
+        return Some(Err(Errno::ECHILD));
     }
+
+    let child_pid = children
+        .iter()
+        .copied()
+        .find(|child| task::with_task(*child, |t| t.state == TaskState::Zombie) == Some(true))?;
+
+    // Store the status before reaping, so a bad pointer leaves the zombie in place.
+    if wstatus != 0 {
+        let status = task::with_task(child_pid, |t| t.exit_status).unwrap_or(0);
+        if let Err(err) = uaccess::put_user(wstatus, status) {
+            return Some(Err(err));
+        }
+    }
+    // The child may still be switching away on its CPU. Once it is off, dropping the task
+    // frees its kernel stack, nothing else is left by now.
+    scheduler::wait_off_cpu(child_pid);
+    let child = task::remove_task(child_pid).expect("zombie vanished");
+    klog!(
+        Debug,
+        "sys_wait4: reaped pid {} with status {:#x}",
+        child_pid,
+        child.exit_status
+    );
+    Some(Ok(child_pid))
 }
 
-fn sys_open(pathname: u64, flags: u64, _mode: u64) -> u64 {
-    let task = match get_current_task() {
-        Some(t) => t,
-        None => return u64::MAX,
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_PROCESS_CPUTIME_ID: u64 = 2;
+const CLOCK_THREAD_CPUTIME_ID: u64 = 3;
+const CLOCK_MONOTONIC_RAW: u64 = 4;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const CLOCK_MONOTONIC_COARSE: u64 = 6;
+const CLOCK_BOOTTIME: u64 = 7;
+
+fn sys_clock_gettime(clock_id: u64, tp: u64) -> KResult<u64> {
+    let ns = match clock_id {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => time::realtime_ns(),
+        // Nothing suspends, so boot time and monotonic time are the same.
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            time::monotonic_ns()
+        }
+        // Tasks are single-threaded.
+        CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => scheduler::cpu_time_ns(getpid()),
+        _ => return Err(Errno::EINVAL),
     };
+    uaccess::put_user(tp, time::Timespec::from_ns(ns))?;
+    Ok(0)
+}
 
-    let mut path_len = 0;
-    unsafe {
-        let mut ptr = pathname as *const u8;
-        while *ptr != 0 && path_len < 256 {
-            path_len += 1;
-            ptr = ptr.add(1);
-        }
+// The timezone argument is obsolete; it is reported as UTC.
+fn sys_gettimeofday(tv: u64, tz: u64) -> KResult<u64> {
+    if tv != 0 {
+        uaccess::put_user(tv, time::Timeval::from_ns(time::realtime_ns()))?;
+    }
+    if tz != 0 {
+        uaccess::put_user(tz, [0i32; 2])?;
     }
+    Ok(0)
+}
 
-    if path_len == 0 || path_len >= 256 {
-        return u64::MAX;
+fn sys_time(tloc: u64) -> KResult<u64> {
+    let seconds = time::now().tv_sec;
+    if tloc != 0 {
+        uaccess::put_user(tloc, seconds)?;
     }
+    Ok(seconds as u64)
+}
 
-    let path_slice = unsafe { core::slice::from_raw_parts(pathname as *const u8, path_len) };
-    let path_str = match core::str::from_utf8(path_slice) {
-        Ok(s) => s,
-        Err(_) => return u64::MAX,
-    };
+// Read from a file descriptor
+// sys_read(fd, buf, count)
+fn sys_read(fd: u64, buf: u64, count: u64) -> KResult<u64> {
+    let file = current_file(fd)?;
+
+    let result = read_to_user(buf, count as usize, |chunk| {
+        let mut vfs_guard = vfs::lock();
+        vfs::read_file(file.get_mut(&mut vfs_guard), chunk)
+    })?;
+
+    Ok(result as u64)
+}
+
+const O_NOFOLLOW: u64 = 0o400000;
+
+fn sys_open(pathname: u64, flags: u64, _mode: u64) -> KResult<u64> {
+    let path_str = read_user_c_string(pathname, PATH_MAX)?;
 
     klog!(
         Debug,
@@ -269,10 +571,17 @@ fn sys_open(pathname: u64, flags: u64, _mode: u64) -> u64 {
         flags
     );
 
-    let dentry = vfs::resolve_path(path_str);
-    if dentry.is_null() {
-        klog!(Debug, "sys_open: path not found");
-        return u64::MAX;
+    let lookup_flags = if flags & O_NOFOLLOW != 0 {
+        0
+    } else {
+        namei::LOOKUP_FOLLOW
+    };
+    let vfs_guard = vfs::lock();
+    let dentry = namei::path_lookup(&path_str, lookup_flags).inspect_err(|err| {
+        klog!(Debug, "sys_open: path not found ({:?})", err);
+    })?;
+    if vfs::is_symlink(unsafe { (*dentry).d_inode }) {
+        return Err(Errno::ELOOP);
     }
 
     let fmode = match flags & 3 {
@@ -283,44 +592,210 @@ fn sys_open(pathname: u64, flags: u64, _mode: u64) -> u64 {
     };
 
     // Open the file
-    let file = match vfs::open_file(dentry, fmode) {
-        Some(f) => f,
-        None => {
-            klog!(Debug, "sys_open: failed to open file");
-            return u64::MAX;
-        }
-    };
-
-    // Allocate a file descriptor
-    let fd = task.next_fd;
-    task.next_fd += 1;
-
-    // Add to file descriptor table
-    task.file_descriptors.insert(fd, file);
+    let file = vfs::open_file(dentry, fmode).inspect_err(|err| {
+        klog!(Debug, "sys_open: failed to open file ({:?})", err);
+    })?;
+    drop(vfs_guard);
+    let file = OpenFile::new(file);
+
+    let fd = with_current_task(|task| {
+        // Allocate a file descriptor
+        let fd = task.next_fd;
+        task.next_fd += 1;
+
+        // Add to file descriptor table
+        task.file_descriptors.insert(fd, file);
+        fd
+    })
+    .ok_or(Errno::ESRCH)?;
 
     klog!(Debug, "sys_open: opened file with fd={}", fd);
-    fd
+    Ok(fd)
 }
 
 // Close a file descriptor
 // sys_close(fd)
-fn sys_close(fd: u64) -> u64 {
-    let task = match get_current_task() {
-        Some(t) => t,
-        None => return u64::MAX,
-    };
-
+fn sys_close(fd: u64) -> KResult<u64> {
     // Don't allow closing stdin/stdout/stderr
     if fd < 3 {
-        return u64::MAX;
+        return Err(Errno::EBADF);
     }
 
-    match task.file_descriptors.remove(&fd) {
-        Some(file) => {
-            vfs::close_file(file);
-            klog!(Debug, "sys_close: closed fd={}", fd);
-            0
+    // The file is closed once its mappings are gone as well.
+    let file = with_current_task(|task| task.file_descriptors.remove(&fd))
+        .ok_or(Errno::ESRCH)?
+        .ok_or(Errno::EBADF)?;
+    drop(file);
+    klog!(Debug, "sys_close: closed fd={}", fd);
+    Ok(0)
+}
+
+// Make `dir` the working directory of the current task. The VFS lock is held.
+fn set_cwd(dir: *mut Dentry) -> KResult<u64> {
+    if !vfs::is_dir(unsafe { (*dir).d_inode }) {
+        return Err(Errno::ENOTDIR);
+    }
+    let new_cwd = vfs::dget(dir);
+    match with_current_task(|task| core::mem::replace(&mut task.cwd, new_cwd)) {
+        Some(old_cwd) => {
+            vfs::dput(old_cwd);
+            Ok(0)
+        }
+        None => {
+            vfs::dput(new_cwd);
+            Err(Errno::ESRCH)
         }
-        None => u64::MAX,
     }
 }
+
+fn sys_chdir(pathname: u64) -> KResult<u64> {
+    let path_str = read_user_c_string(pathname, PATH_MAX)?;
+    let _vfs = vfs::lock();
+    set_cwd(namei::path_lookup(&path_str, namei::LOOKUP_FOLLOW)?)
+}
+
+fn sys_fchdir(fd: u64) -> KResult<u64> {
+    let file = current_file(fd)?;
+    let vfs_guard = vfs::lock();
+    let file = file.get(&vfs_guard);
+    if !vfs::is_dir(file.f_inode) {
+        return Err(Errno::ENOTDIR);
+    }
+    // Directories have a single name; none if it was removed while open.
+    let dir = unsafe { (*file.f_inode).i_dentry.front().copied() }.ok_or(Errno::ENOENT)?;
+    set_cwd(dir)
+}
+
+// Copy the working directory's path, NUL-terminated, to `buf`. Returns the length including
+// the terminator, as the raw Linux syscall does.
+fn sys_getcwd(buf: u64, size: u64) -> KResult<u64> {
+    let vfs_guard = vfs::lock();
+    let (root, cwd) = namei::current_root_and_cwd();
+    let mut path = vfs::dentry_path(cwd, root).into_bytes();
+    drop(vfs_guard);
+    path.push(0);
+    if path.len() as u64 > size {
+        return Err(Errno::ERANGE);
+    }
+    uaccess::copy_to_user(buf, &path)?;
+    Ok(path.len() as u64)
+}
+
+const MS_REMOUNT: u64 = 32;
+const MS_BIND: u64 = 4096;
+const MS_MOVE: u64 = 8192;
+
+// Only fresh mounts are supported. Filesystems don't take a source device or options yet, so
+// `source` and `data` are ignored.
+fn sys_mount(
+    _source: u64,
+    target: u64,
+    filesystemtype: u64,
+    flags: u64,
+    _data: u64,
+) -> KResult<u64> {
+    if flags & (MS_REMOUNT | MS_BIND | MS_MOVE) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let target_str = read_user_c_string(target, PATH_MAX)?;
+    let fs_name = read_user_c_string(filesystemtype, PATH_MAX)?;
+
+    let vfs_guard = vfs::lock();
+    let dir = namei::path_lookup(&target_str, namei::LOOKUP_FOLLOW)?;
+    vfs::mount(&fs_name, vfs::anonymous_dev(), dir)?;
+    drop(vfs_guard);
+    klog!(Debug, "sys_mount: mounted {} on {}", fs_name, target_str);
+    Ok(0)
+}
+
+const MNT_FORCE: u64 = 1;
+const UMOUNT_NOFOLLOW: u64 = 8;
+
+fn sys_umount2(target: u64, flags: u64) -> KResult<u64> {
+    if flags & !(MNT_FORCE | UMOUNT_NOFOLLOW) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let target_str = read_user_c_string(target, PATH_MAX)?;
+
+    let lookup_flags = if flags & UMOUNT_NOFOLLOW != 0 {
+        0
+    } else {
+        namei::LOOKUP_FOLLOW
+    };
+    let vfs_guard = vfs::lock();
+    vfs::umount(namei::path_lookup(&target_str, lookup_flags)?)?;
+    drop(vfs_guard);
+    klog!(Debug, "sys_umount2: unmounted {}", target_str);
+    Ok(0)
+}
+
+// stat and lstat; they differ only in following a symbolic link at the end of the path.
+fn sys_stat(pathname: u64, statbuf: u64, lookup_flags: u32) -> KResult<u64> {
+    let path_str = read_user_c_string(pathname, PATH_MAX)?;
+    let vfs_guard = vfs::lock();
+    let dentry = namei::path_lookup(&path_str, lookup_flags)?;
+    let inode = unsafe { (*dentry).d_inode.as_ref() }.ok_or(Errno::ENOENT)?;
+    let stat = stat::stat(inode);
+    drop(vfs_guard);
+    uaccess::put_user(statbuf, stat)?;
+    Ok(0)
+}
+
+fn sys_fstat(fd: u64, statbuf: u64) -> KResult<u64> {
+    let file = current_file(fd)?;
+    let vfs_guard = vfs::lock();
+    let inode = unsafe { file.get(&vfs_guard).f_inode.as_ref() }.ok_or(Errno::EBADF)?;
+    let stat = stat::stat(inode);
+    drop(vfs_guard);
+    uaccess::put_user(statbuf, stat)?;
+    Ok(0)
+}
+
+const AT_FDCWD: i32 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// Every field is filled in whatever `mask` asks for; the returned mask says which are valid.
+fn sys_statx(dirfd: u64, pathname: u64, flags: u64, _mask: u64, statxbuf: u64) -> KResult<u64> {
+    let path_str = read_user_c_string(pathname, PATH_MAX)?;
+    let dirfd = dirfd as i32;
+    let dir_file = (dirfd != AT_FDCWD).then(|| current_file(dirfd as u64));
+    let vfs_guard = vfs::lock();
+    let (root, cwd) = namei::current_root_and_cwd();
+
+    // Inode of `dirfd`, or of the working directory for AT_FDCWD.
+    let dir_inode = || -> KResult<*mut Inode> {
+        match &dir_file {
+            None => Ok(unsafe { (*cwd).d_inode }),
+            Some(file) => Ok(file.as_ref().map_err(|err| *err)?.get(&vfs_guard).f_inode),
+        }
+    };
+
+    let inode = if path_str.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        dir_inode()?
+    } else {
+        // Relative paths start at `dirfd`; absolute ones don't look at it.
+        let start = if path_str.starts_with('/') || dirfd == AT_FDCWD {
+            cwd
+        } else {
+            let dir = dir_inode()?;
+            if !vfs::is_dir(dir) {
+                return Err(Errno::ENOTDIR);
+            }
+            unsafe { (*dir).i_dentry.front().copied() }.ok_or(Errno::ENOENT)?
+        };
+        let lookup_flags = if flags & AT_SYMLINK_NOFOLLOW != 0 {
+            0
+        } else {
+            namei::LOOKUP_FOLLOW
+        };
+        let dentry = namei::path_lookup_at(root, start, &path_str, lookup_flags)?;
+        unsafe { (*dentry).d_inode }
+    };
+
+    let inode = unsafe { inode.as_ref() }.ok_or(Errno::ENOENT)?;
+    let statx = stat::statx(inode);
+    drop(vfs_guard);
+    uaccess::put_user(statxbuf, statx)?;
+    Ok(0)
+}
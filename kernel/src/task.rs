@@ -1,13 +1,32 @@
-use crate::fs::file::File;
-use crate::memory::create_user_page_table_with_mapper;
+use crate::errno::{Errno, KResult};
+use crate::fs::dentry::Dentry;
+use crate::fs::file::OpenFile;
+use crate::fs::vfs;
+use crate::gdt;
+use crate::klog;
+use crate::memory;
+use crate::memory::{
+    create_user_page_table_with_mapper, frame_ref_count, free_user_page_tables, release_frame,
+    share_frame, user_leaf_entries, user_leaf_entry, COW_FLAG, PHYSICAL_MEMORY_OFFSET,
+};
+use crate::percpu;
+use crate::scheduler;
+use crate::sync::SpinLock;
+use crate::vma::{Vma, VmaKind, VmaList};
 use alloc::boxed::Box;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
 use x86_64::VirtAddr;
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct TrapFrame {
     pub r15: u64,
     pub r14: u64,
@@ -31,17 +50,59 @@ pub struct TrapFrame {
     pub ss: u64,
 }
 
+pub const KERNEL_STACK_SIZE: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Runnable,
+    Running,
+    Blocked,
+    // Exited, waiting for the parent to collect the status with wait4.
+    Zombie,
+}
+
+// Orphaned children are handed to init.
+pub const INIT_PID: u64 = 1;
+
 #[allow(dead_code)]
 pub struct Task {
     pub pid: u64,
     pub ppid: u64,
+    pub state: TaskState,
     pub trap_frame: *mut TrapFrame,
     pub page_table: OffsetPageTable<'static>,
     pub phys_pages: Vec<PhysFrame>,
-    pub file_descriptors: BTreeMap<u64, Box<File>>,
+    // Regions of the user address space that may be accessed; see `handle_demand_fault`.
+    pub vmas: VmaList,
+    // Areas taken out of the address space with the task table locked. Dropping a file mapping
+    // may close the file, which sleeps on the VFS lock, so `with_task` drops them after unlocking.
+    pub unmapped: Vec<Vma>,
+    // Program break: the heap VMA spans [brk_start, brk rounded up to a page).
+    pub brk_start: u64,
+    pub brk: u64,
+    pub file_descriptors: BTreeMap<u64, Arc<OpenFile>>,
     pub next_fd: u64,
+    // Where relative and absolute paths start. Null until first set, which means the global
+    // root; both hold a dentry reference otherwise.
+    pub cwd: *mut Dentry,
+    pub root: *mut Dentry,
+    // Every task owns its kernel stack; syscalls and interrupts taken while the task runs use it,
+    // and the scheduler parks the callee-saved registers on it when switching away.
+    pub kernel_stack: Box<[u8]>,
+    pub saved_rsp: u64,
+    // Set while some CPU runs on this task's kernel stack, including the tail of switching away
+    // from it. The scheduler never picks a task that is still on a CPU.
+    pub on_cpu: bool,
+    // Time spent running, not counting the current stint on a CPU (see `cpu_time_ns`).
+    pub cpu_time_ns: u64,
+    // Status reported by wait4 once the task is a zombie, encoded like Linux does.
+    pub exit_status: u32,
 }
 
+// The raw pointers in a task (trap frame, open files, directories) point into memory the task
+// owns or holds a reference to.
+unsafe impl Send for Task {}
+
 impl Task {
     pub fn new(
         pid: u64,
@@ -52,69 +113,517 @@ impl Task {
         Task {
             pid,
             ppid,
+            state: TaskState::Blocked,
             trap_frame: core::ptr::null_mut(),
             page_table: create_user_page_table_with_mapper(frame_allocator, physical_memory_offset)
                 .unwrap(),
             phys_pages: Vec::new(),
+            vmas: VmaList::new(),
+            unmapped: Vec::new(),
+            brk_start: 0,
+            brk: 0,
             file_descriptors: BTreeMap::new(),
             next_fd: 3, // Start at 3 (0, 1, 2 are stdin, stdout, stderr)
+            cwd: core::ptr::null_mut(),
+            root: core::ptr::null_mut(),
+            kernel_stack: vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
+            saved_rsp: 0,
+            on_cpu: false,
+            cpu_time_ns: 0,
+            exit_status: 0,
+        }
+    }
+
+    // Drop every user mapping and give the frames back, e.g. before loading a new image.
+    pub fn release_user_memory(&mut self, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+        free_user_page_tables(&mut self.page_table, frame_allocator);
+        for frame in self.phys_pages.drain(..) {
+            release_frame(frame, frame_allocator);
+        }
+        let vmas = self.vmas.drain();
+        self.unmapped.extend(vmas);
+    }
+
+    // Release the whole address space, the top-level table included. The task must not be
+    // running on it.
+    pub fn free_address_space(&mut self, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+        self.release_user_memory(frame_allocator);
+        unsafe {
+            frame_allocator.deallocate_frame(memory::page_table_frame(&self.page_table));
+        }
+    }
+
+    // The area a fault at `addr` is resolved from: the page is not mapped yet, and the area
+    // permits the access.
+    fn fault_vma(&mut self, addr: VirtAddr, write: bool, exec: bool) -> Result<Vma, u32> {
+        let vma = self.vmas.find(addr.as_u64()).ok_or(SIGSEGV)?;
+        if !vma.allows(write, exec) || user_leaf_entry(&mut self.page_table, addr).is_some() {
+            return Err(SIGSEGV);
+        }
+        Ok(vma.clone())
+    }
+
+    pub fn map_zeroed_page(
+        &mut self,
+        addr: VirtAddr,
+        flags: PageTableFlags,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> bool {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        unsafe {
+            core::ptr::write_bytes(
+                (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8,
+                0,
+                4096,
+            );
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe { self.page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return false;
+            }
+        }
+        self.phys_pages.push(frame);
+        true
+    }
+
+    // Map `frame`, which somebody else (e.g. a file) holds on to as well, and take a reference.
+    pub fn map_shared_frame(
+        &mut self,
+        addr: VirtAddr,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> bool {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe { self.page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => return false,
+        }
+        share_frame(frame);
+        self.phys_pages.push(frame);
+        true
+    }
+
+    // Drop [start, end) from the address space: the VMAs covering it and every page mapped there.
+    pub fn unmap_region(
+        &mut self,
+        start: u64,
+        end: u64,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        let mut released = BTreeSet::new();
+        // Every user mapping lies inside a VMA, so only the removed areas need to be walked.
+        for vma in self.vmas.remove_range(start, end) {
+            for addr in (vma.start..vma.end).step_by(4096) {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+                if user_leaf_entry(&mut self.page_table, page.start_address()).is_none() {
+                    continue;
+                }
+                if let Ok((frame, flush)) = self.page_table.unmap(page) {
+                    flush.flush();
+                    released.insert(frame);
+                    release_frame(frame, frame_allocator);
+                }
+            }
+            self.unmapped.push(vma);
+        }
+        if !released.is_empty() {
+            self.phys_pages.retain(|frame| !released.contains(frame));
+        }
+    }
+
+    // Rewrite the flags of every mapped page in [start, end) from the protection of its VMA.
+    pub fn apply_vma_protection(&mut self, start: u64, end: u64) {
+        for addr in (start..end).step_by(4096) {
+            let vma = match self.vmas.find(addr) {
+                Some(vma) => vma.clone(),
+                None => continue,
+            };
+            let virt = VirtAddr::new(addr);
+            if let Some(entry) = user_leaf_entry(&mut self.page_table, virt) {
+                let mut flags = vma.page_flags();
+                // A private page still shared after fork stays read-only until it is written.
+                let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+                if flags.contains(PageTableFlags::WRITABLE)
+                    && !vma.shared
+                    && frame_ref_count(frame) > 1
+                {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COW_FLAG);
+                }
+                entry.set_flags(flags);
+                x86_64::instructions::tlb::flush(virt);
+            }
+        }
+    }
+
+    // Resolve a write fault on a copy-on-write page. Returns false if `addr` isn't a COW page
+    // of this task (or memory ran out), in which case the fault is a real one.
+    pub fn handle_cow_fault(
+        &mut self,
+        addr: VirtAddr,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> bool {
+        let entry = match user_leaf_entry(&mut self.page_table, addr) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let mut flags = entry.flags();
+        if !flags.contains(COW_FLAG) {
+            return false;
+        }
+        flags.remove(COW_FLAG);
+        flags.insert(PageTableFlags::WRITABLE);
+
+        let old_frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+        if frame_ref_count(old_frame) == 1 {
+            // Everybody else already took their copy, this one can be written in place.
+            entry.set_flags(flags);
+        } else {
+            let new_frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (PHYSICAL_MEMORY_OFFSET + old_frame.start_address().as_u64()) as *const u8,
+                    (PHYSICAL_MEMORY_OFFSET + new_frame.start_address().as_u64()) as *mut u8,
+                    4096,
+                );
+            }
+            entry.set_addr(new_frame.start_address(), flags);
+
+            if let Some(slot) = self.phys_pages.iter_mut().find(|f| **f == old_frame) {
+                *slot = new_frame;
+            }
+            release_frame(old_frame, frame_allocator);
+        }
+
+        x86_64::instructions::tlb::flush(addr);
+        true
+    }
+
+    pub fn kernel_stack_top(&self) -> u64 {
+        // The stack grows down from the end of the allocation; keep it 16-byte aligned.
+        (self.kernel_stack.as_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xF
+    }
+
+    // Lay out the kernel stack so that the first switch to this task "returns" into
+    // `scheduler::task_entry`, which ends up in `interrupt_return` to pop the trap frame below
+    // and iret to ring 3.
+    pub fn prepare_user_entry(&mut self, user_rip: u64, user_rsp: u64) {
+        let frame = TrapFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            rip: user_rip,
+            cs: gdt::selectors().user_code_selector.0 as u64,
+            rflags: 0x202, // IF set
+            rsp: user_rsp,
+            ss: gdt::selectors().user_data_selector.0 as u64,
+        };
+        self.prepare_entry_with_frame(frame);
+    }
+
+    pub fn prepare_entry_with_frame(&mut self, frame: TrapFrame) {
+        let top = self.kernel_stack_top();
+        let frame_addr = top - core::mem::size_of::<TrapFrame>() as u64;
+        unsafe {
+            core::ptr::write(frame_addr as *mut TrapFrame, frame);
+        }
+        self.trap_frame = frame_addr as *mut TrapFrame;
+
+        let mut sp = frame_addr;
+        sp -= 8;
+        unsafe {
+            *(sp as *mut u64) = scheduler::task_entry as *const () as u64;
+        }
+
+        // rbp, rbx, r12, r13, r14, r15 as popped by `switch_context`
+        for _ in 0..6 {
+            sp -= 8;
+            unsafe {
+                *(sp as *mut u64) = 0;
+            }
         }
+
+        self.saved_rsp = sp;
     }
 }
 
-static mut TASKS: BTreeMap<u64, Task> = BTreeMap::new();
+// Every task by pid. Tasks are only touched with the lock held, see `with_task`.
+static TASKS: SpinLock<BTreeMap<u64, Box<Task>>> = SpinLock::new(BTreeMap::new());
 
-static mut CURRENT_TASK: u64 = 0;
-static mut NEXT_PID: u64 = 1;
-static mut PID_MAX: u64 = 0x0400_0000;
+// Where the search for a free pid starts. Only updated with TASKS locked.
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+const PID_MAX: u64 = 0x0400_0000;
 
-#[allow(static_mut_refs)]
+fn alloc_pid(tasks: &BTreeMap<u64, Box<Task>>) -> u64 {
+    let mut pid = NEXT_PID.load(Ordering::Relaxed);
+    while tasks.contains_key(&pid) {
+        pid += 1;
+        if pid >= PID_MAX {
+            pid = 1;
+        }
+    }
+    NEXT_PID.store(pid, Ordering::Relaxed);
+    pid
+}
 
 pub fn create_task(
     ppid: u64,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
     physical_memory_offset: VirtAddr,
 ) -> u64 {
-    unsafe {
-        while TASKS.contains_key(&NEXT_PID) {
-            NEXT_PID += 1;
-            if NEXT_PID >= PID_MAX {
-                NEXT_PID = 1;
+    let mut tasks = TASKS.lock();
+    let pid = alloc_pid(&tasks);
+    tasks.insert(
+        pid,
+        Box::new(Task::new(
+            pid,
+            ppid,
+            frame_allocator,
+            physical_memory_offset,
+        )),
+    );
+    pid
+}
+
+// Run `f` on task `pid` with the task table locked. `f` must neither sleep nor touch user
+// memory: a page fault would need the lock again. Areas it unmapped are dropped once the lock
+// is released, so callers must not hold the VFS lock when `f` may unmap a file.
+pub fn with_task<R>(pid: u64, f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    let mut tasks = TASKS.lock();
+    let task = tasks.get_mut(&pid)?;
+    let result = f(task);
+    let unmapped = core::mem::take(&mut task.unmapped);
+    drop(tasks);
+    drop(unmapped);
+    Some(result)
+}
+
+pub fn with_current_task<R>(f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    with_task(getpid(), f)
+}
+
+// Resolve a page fault of the current task at a user address: writes to shared pages get a
+// private copy, and untouched pages inside a VMA get mapped. An error is the signal the access
+// deserves.
+pub fn resolve_page_fault(addr: VirtAddr, write: bool, exec: bool) -> Result<(), u32> {
+    let mut frame_allocator = memory::frame_allocator();
+    if write
+        && with_current_task(|task| task.handle_cow_fault(addr, &mut frame_allocator)) == Some(true)
+    {
+        return Ok(());
+    }
+    handle_demand_fault(addr, write, exec)
+}
+
+// Map the page at `addr` of the current task if it is not mapped yet, lies inside one of its
+// VMAs and the VMA permits the access. File mappings get the file's page, or SIGBUS past its
+// end; other VMAs get a zeroed frame.
+pub fn handle_demand_fault(addr: VirtAddr, write: bool, exec: bool) -> Result<(), u32> {
+    let vma =
+        with_current_task(|task| task.fault_vma(addr, write, exec)).unwrap_or(Err(SIGSEGV))?;
+    let mut frame_allocator = memory::frame_allocator();
+
+    let mapped = match (&vma.kind, &vma.file) {
+        (VmaKind::File, Some(file)) => {
+            // The file may have grown or shrunk since it was mapped. The VFS lock may sleep, so it
+            // is taken before the task table; holding it until the page is mapped keeps the file
+            // from being truncated under us.
+            let vfs_guard = vfs::lock();
+            let file = file.get(&vfs_guard);
+            let offset = vma.file_offset + (addr.align_down(4096u64).as_u64() - vma.start);
+            if offset >= unsafe { (*file.f_inode).i_size } {
+                return Err(SIGBUS);
             }
+            let frame = vfs::file_page(file, offset / 4096).map_err(|_| SIGBUS)?;
+            let flags = vma.file_page_flags();
+            with_current_task(|task| {
+                task.map_shared_frame(addr, frame, flags, &mut frame_allocator)
+            })
         }
-
-        TASKS.insert(
-            NEXT_PID,
-            Task::new(NEXT_PID, ppid, frame_allocator, physical_memory_offset),
-        );
-        NEXT_PID
+        _ => with_current_task(|task| {
+            task.map_zeroed_page(addr, vma.page_flags(), &mut frame_allocator)
+        }),
+    };
+    if mapped == Some(true) {
+        Ok(())
+    } else {
+        Err(SIGSEGV)
     }
 }
 
-// need to use those getters since those will at some point become per-core
+// Duplicate the current task into a new one that resumes from `frame` with rax = 0. User pages
+// are shared: writable ones lose WRITABLE in both address spaces and get COW_FLAG, so the first
+// write on either side faults and takes a private copy. Pages of MAP_SHARED areas stay writable
+// in both.
+pub fn fork_current(
+    frame: &TrapFrame,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> KResult<u64> {
+    let parent_pid = getpid();
+    // Taking references to the directories needs the VFS lock, which goes before the task table.
+    let _vfs = vfs::lock();
+    let mut tasks = TASKS.lock();
+    let child_pid = alloc_pid(&tasks);
+    let parent = tasks.get_mut(&parent_pid).ok_or(Errno::ESRCH)?;
+    let mut child = Box::new(Task::new(
+        child_pid,
+        parent_pid,
+        frame_allocator,
+        parent.page_table.phys_offset(),
+    ));
 
-pub fn getpid() -> u64 {
-    unsafe { CURRENT_TASK }
+    for (addr, entry) in user_leaf_entries(&mut parent.page_table) {
+        let mut flags = entry.flags();
+        let shared = parent
+            .vmas
+            .find(addr.as_u64())
+            .is_some_and(|vma| vma.shared);
+        if flags.contains(PageTableFlags::WRITABLE) && !shared {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COW_FLAG);
+            entry.set_flags(flags);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let phys = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+        let mapped = unsafe { child.page_table.map_to(page, phys, flags, frame_allocator) };
+        match mapped {
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                x86_64::instructions::tlb::flush_all();
+                child.free_address_space(frame_allocator);
+                return Err(Errno::ENOMEM);
+            }
+        }
+        share_frame(phys);
+        child.phys_pages.push(phys);
+    }
+
+    // The parent's writable mappings just became read-only.
+    x86_64::instructions::tlb::flush_all();
+
+    // Parent and child share their open files, offsets included.
+    child.file_descriptors = parent.file_descriptors.clone();
+    child.next_fd = parent.next_fd;
+    child.cwd = vfs::dget(parent.cwd);
+    child.root = vfs::dget(parent.root);
+    child.vmas = parent.vmas.clone();
+    child.brk_start = parent.brk_start;
+    child.brk = parent.brk;
+
+    child.prepare_entry_with_frame(TrapFrame { rax: 0, ..*frame });
+    tasks.insert(child_pid, child);
+
+    Ok(child_pid)
 }
 
-pub fn getppid() -> u64 {
+// wait4 statuses of tasks killed by a CPU exception, i.e. the number of the signal Linux would
+// have sent.
+pub const SIGILL: u32 = 4;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGSEGV: u32 = 11;
+
+// Terminate the current task: close its files, give back all of its memory, turn it into a
+// zombie and let the parent know. The kernel stack is freed when the parent reaps it.
+pub fn exit_current(wait_status: u32) -> ! {
     let pid = getpid();
-    #[allow(static_mut_refs)]
-    unsafe {
-        TASKS.get(&pid).map(|task| task.ppid).unwrap_or(0)
+    let mut frame_allocator = memory::frame_allocator();
+
+    // Files, and the files of mappings, are closed after the task table is unlocked. That may
+    // sleep, so the page table has to stay until they are gone.
+    let (files, cwd, root) = with_current_task(|task| {
+        task.release_user_memory(&mut frame_allocator);
+        (
+            core::mem::take(&mut task.file_descriptors),
+            core::mem::replace(&mut task.cwd, core::ptr::null_mut()),
+            core::mem::replace(&mut task.root, core::ptr::null_mut()),
+        )
+    })
+    .expect("exit without a current task");
+    drop(files);
+    let vfs_guard = vfs::lock();
+    vfs::dput(cwd);
+    vfs::dput(root);
+    drop(vfs_guard);
+
+    // The PML4 can only go once we are no longer running on it.
+    with_current_task(|task| {
+        memory::switch_to_kernel_page_table();
+        task.free_address_space(&mut frame_allocator);
+    });
+
+    if pid == INIT_PID {
+        klog!(Warn, "init exited with status {:#x}", wait_status);
+    }
+
+    let mut orphaned_zombie = false;
+    for child in TASKS.lock().values_mut().filter(|task| task.ppid == pid) {
+        child.ppid = INIT_PID;
+        orphaned_zombie |= child.state == TaskState::Zombie;
+    }
+    if orphaned_zombie {
+        scheduler::wake(INIT_PID);
     }
+
+    let ppid = with_current_task(|task| {
+        task.exit_status = wait_status;
+        task.state = TaskState::Zombie;
+        task.ppid
+    })
+    .expect("exit without a current task");
+    scheduler::wake(ppid);
+
+    scheduler::schedule();
+    unreachable!("zombie task {} was scheduled", pid);
+}
+
+pub fn children_of(pid: u64) -> Vec<u64> {
+    TASKS
+        .lock()
+        .values()
+        .filter(|task| task.ppid == pid)
+        .map(|task| task.pid)
+        .collect()
+}
+
+pub fn remove_task(pid: u64) -> Option<Box<Task>> {
+    TASKS.lock().remove(&pid)
 }
 
-pub fn get_current_task() -> Option<&'static mut Task> {
+pub fn getpid() -> u64 {
+    percpu::this_cpu().current_pid
+}
+
+pub fn getppid() -> u64 {
     let pid = getpid();
-    #[allow(static_mut_refs)]
-    unsafe {
-        TASKS.get_mut(&pid)
-    }
+    TASKS.lock().get(&pid).map(|task| task.ppid).unwrap_or(0)
 }
 
 pub fn set_current_pid(pid: u64) {
-    unsafe {
-        CURRENT_TASK = pid;
-    }
+    percpu::this_cpu().current_pid = pid;
 }
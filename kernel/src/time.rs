@@ -1,26 +1,256 @@
-pub static mut PIT_TICK_COUNT: u64 = 0;
+use crate::acpi;
+use crate::klog;
+use crate::memory;
+use crate::rtc;
+use crate::sync::OnceCell;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
 
-pub static mut PIT_COUNT: u64 = 0;
-pub static PIT_FREQUENCY: u64 = 1193182;
+pub const PIT_FREQUENCY: u64 = 1193182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+const PIT_RATE_GENERATOR: u8 = 0x34;
 
-pub fn set_pit_tick_count(count: u64) {
-    unsafe {
-        PIT_TICK_COUNT = count;
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+static PIT_TICKS: AtomicU64 = AtomicU64::new(0);
+static PIT_DIVISOR: AtomicU64 = AtomicU64::new(65536);
 
-        let mut port = x86_64::instructions::port::Port::new(0x40);
-        port.write(count as u8);
-        port.write((count >> 8) as u8);
+// Program the PIT to interrupt every `divisor` input clocks, 1 to 65536.
+pub fn set_pit_divisor(divisor: u32) {
+    let divisor = divisor.clamp(1, 65536);
+    PIT_DIVISOR.store(divisor as u64, Ordering::Relaxed);
+    // A reload value of 0 stands for 65536.
+    let reload = divisor as u16;
+    unsafe {
+        Port::new(PIT_COMMAND).write(PIT_RATE_GENERATOR);
+        let mut channel0 = Port::new(PIT_CHANNEL0);
+        channel0.write(reload as u8);
+        channel0.write((reload >> 8) as u8);
     }
 }
 
+// Called from the PIT interrupt.
+pub fn pit_tick() {
+    PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
 pub fn get_pit_tick_count() -> u64 {
-    unsafe { PIT_TICK_COUNT }
+    PIT_TICKS.load(Ordering::Relaxed)
+}
+
+pub fn pit_period_ns() -> u64 {
+    PIT_DIVISOR.load(Ordering::Relaxed) * NS_PER_SEC / PIT_FREQUENCY
+}
+
+// A free-running counter the monotonic clock is derived from.
+#[derive(Clone, Copy)]
+pub struct ClockSource {
+    pub name: &'static str,
+    read: fn() -> u64,
+    // Counter increments per second.
+    pub frequency: u64,
+}
+
+struct Clock {
+    source: ClockSource,
+    // Counter value and clock reading at the moment the source was installed, so the clock
+    // carries on from the PIT-based time instead of jumping.
+    base_count: u64,
+    base_ns: u64,
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::new();
+
+// Nanoseconds since boot. Until `init_clocksource` has picked something better this only has
+// PIT tick resolution.
+pub fn monotonic_ns() -> u64 {
+    match CLOCK.get() {
+        Some(clock) => {
+            let elapsed = (clock.source.read)().wrapping_sub(clock.base_count);
+            clock.base_ns
+                + (elapsed as u128 * NS_PER_SEC as u128 / clock.source.frequency as u128) as u64
+        }
+        None => get_pit_tick_count() * pit_period_ns(),
+    }
+}
+
+pub fn clocksource() -> Option<ClockSource> {
+    CLOCK.get().map(|clock| clock.source)
+}
+
+fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// An invariant TSC runs at a constant rate in every power state, and the same on all cores.
+fn tsc_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIG: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xF0;
+const HPET_COUNTER_64BIT: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1 << 0;
+// The spec caps the tick period at 100 ns.
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+static HPET_BASE: OnceCell<u64> = OnceCell::new();
+
+fn read_hpet() -> u64 {
+    let base = HPET_BASE.get().expect("HPET not mapped");
+    unsafe { core::ptr::read_volatile((base + HPET_MAIN_COUNTER) as *const u64) }
+}
+
+// Map and start the HPET main counter, returning its frequency. Only 64-bit counters are used,
+// so wrapping never has to be dealt with.
+fn init_hpet() -> Option<u64> {
+    let hpet = acpi::info()?.hpet?;
+    if hpet.address.address_space != 0 {
+        return None;
+    }
+    let base = memory::map_mmio(hpet.address.address, 0x400)?;
+
+    let capabilities =
+        unsafe { core::ptr::read_volatile((base + HPET_CAPABILITIES) as *const u64) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS || capabilities & HPET_COUNTER_64BIT == 0 {
+        return None;
+    }
+
+    unsafe {
+        let config = (base + HPET_CONFIG) as *mut u64;
+        core::ptr::write_volatile(config, core::ptr::read_volatile(config) | HPET_ENABLE);
+    }
+    let _ = HPET_BASE.set(base);
+    Some(1_000_000_000_000_000 / period_fs)
+}
+
+// Measure the TSC against the HPET over 50 ms, or over a few PIT ticks without one. The PIT
+// variant needs the timer interrupt running.
+fn calibrate_tsc(hpet: Option<ClockSource>) -> u64 {
+    if let Some(hpet) = hpet {
+        let hpet_start = read_hpet();
+        let tsc_start = read_tsc();
+        while read_hpet() - hpet_start < hpet.frequency / 20 {
+            core::hint::spin_loop();
+        }
+        let hpet_elapsed = read_hpet() - hpet_start;
+        let tsc_elapsed = read_tsc() - tsc_start;
+        return (tsc_elapsed as u128 * hpet.frequency as u128 / hpet_elapsed as u128) as u64;
+    }
+
+    const PIT_TICKS_TO_MEASURE: u64 = 4;
+    let wait_for_tick = || {
+        let tick = get_pit_tick_count();
+        while get_pit_tick_count() == tick {
+            core::hint::spin_loop();
+        }
+    };
+    wait_for_tick();
+    let tsc_start = read_tsc();
+    for _ in 0..PIT_TICKS_TO_MEASURE {
+        wait_for_tick();
+    }
+    let tsc_elapsed = read_tsc() - tsc_start;
+    (tsc_elapsed as u128 * NS_PER_SEC as u128 / (PIT_TICKS_TO_MEASURE * pit_period_ns()) as u128)
+        as u64
+}
+
+// Pick the best clock source: the TSC if it is invariant, then the HPET. Without either the
+// clock stays on PIT ticks.
+pub fn init_clocksource() {
+    let hpet = init_hpet().map(|frequency| ClockSource {
+        name: "hpet",
+        read: read_hpet,
+        frequency,
+    });
+    let tsc = tsc_invariant().then(|| ClockSource {
+        name: "tsc",
+        read: read_tsc,
+        frequency: calibrate_tsc(hpet),
+    });
+
+    let Some(source) = tsc.or(hpet) else {
+        klog!(Warn, "No TSC or HPET clock source, using the PIT.");
+        return;
+    };
+
+    let base_ns = monotonic_ns();
+    let _ = CLOCK.set(Clock {
+        source,
+        base_count: (source.read)(),
+        base_ns,
+    });
+    klog!(
+        Info,
+        "Clock source: {} at {} kHz",
+        source.name,
+        source.frequency / 1000
+    );
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Timespec {
+            tv_sec: (ns / NS_PER_SEC) as i64,
+            tv_nsec: (ns % NS_PER_SEC) as i64,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timeval {
+    pub fn from_ns(ns: u64) -> Self {
+        Timeval {
+            tv_sec: (ns / NS_PER_SEC) as i64,
+            tv_usec: (ns % NS_PER_SEC / 1000) as i64,
+        }
+    }
+}
+
+// Wall-clock time at monotonic time 0, in nanoseconds since the Unix epoch.
+static REALTIME_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+// Set the wall clock from the CMOS RTC. Best done once the clock source is up, so the offset
+// is taken against the final monotonic clock.
+pub fn init_realtime() {
+    let now = rtc::read();
+    let unix_ns = now.unix_seconds() * NS_PER_SEC;
+    REALTIME_OFFSET_NS.store(unix_ns.saturating_sub(monotonic_ns()), Ordering::Relaxed);
+    klog!(
+        Info,
+        "RTC time: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        now.year,
+        now.month,
+        now.day,
+        now.hour,
+        now.minute,
+        now.second
+    );
 }
 
-pub fn get_pit_frequency() -> f32 {
-    unsafe { PIT_FREQUENCY as f32 / (if PIT_COUNT != 0 { PIT_COUNT } else { 65536 }) as f32 }
+// Nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    REALTIME_OFFSET_NS.load(Ordering::Relaxed) + monotonic_ns()
 }
 
-pub fn time_since_boot() -> f32 {
-    get_pit_tick_count() as f32 / get_pit_frequency()
+pub fn now() -> Timespec {
+    Timespec::from_ns(realtime_ns())
 }
@@ -0,0 +1,155 @@
+use crate::errno::{Errno, KResult};
+use crate::memory;
+use crate::task;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::naked_asm;
+use core::mem::MaybeUninit;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// First non-canonical address; everything below belongs to user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+extern "C" {
+    static uaccess_copy_insn: u8;
+    static uaccess_copy_fixup: u8;
+}
+
+// Copy `len` bytes and return how many were left over. A page fault on the `rep movsb` is sent
+// to `uaccess_copy_fixup` by `fixup_page_fault`, where rcx still holds the remaining count.
+#[unsafe(naked)]
+unsafe extern "C" fn copy_user_raw(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    naked_asm!(
+        "mov rcx, rdx",
+        ".global uaccess_copy_insn",
+        "uaccess_copy_insn:",
+        "rep movsb",
+        ".global uaccess_copy_fixup",
+        "uaccess_copy_fixup:",
+        "mov rax, rcx",
+        "ret",
+    );
+}
+
+// Called by the page fault handler for faults raised in kernel mode. Returns true if the fault
+// hit a user copy, in which case the copy resumes at its fixup and reports a short count.
+pub fn fixup_page_fault(stack_frame: &mut InterruptStackFrame) -> bool {
+    let insn = &raw const uaccess_copy_insn as u64;
+    if stack_frame.instruction_pointer.as_u64() != insn {
+        return false;
+    }
+
+    let fixup = VirtAddr::new(&raw const uaccess_copy_fixup as u64);
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = fixup);
+    }
+    true
+}
+
+// Check that [addr, addr + len) lies below USER_SPACE_END and that every page in it is mapped
+// user-accessible in the current task. Pages that would be faulted in (demand paging, or a
+// private copy of a copy-on-write page for writes) are resolved here, so the copy itself only
+// faults on pages that really are bad.
+fn check_range(addr: u64, len: usize, write: bool) -> KResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+    if end > USER_SPACE_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let virt = VirtAddr::new(page);
+        // Pages not mapped at all are left to `handle_demand_fault`, which may have to read a file.
+        let mapped = task::with_current_task(|task| {
+            match memory::user_leaf_entry(&mut task.page_table, virt) {
+                Some(entry) if !write || entry.flags().contains(PageTableFlags::WRITABLE) => {
+                    Some(entry.flags().contains(PageTableFlags::USER_ACCESSIBLE))
+                }
+                Some(_) => Some(task.handle_cow_fault(virt, &mut memory::frame_allocator())),
+                None => None,
+            }
+        })
+        .ok_or(Errno::EFAULT)?;
+        let accessible = match mapped {
+            Some(accessible) => accessible,
+            None => task::handle_demand_fault(virt, write, false).is_ok(),
+        };
+        if !accessible {
+            return Err(Errno::EFAULT);
+        }
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> KResult<()> {
+    check_range(src, dst.len(), false)?;
+    let left = unsafe { copy_user_raw(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left != 0 {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> KResult<()> {
+    check_range(dst, src.len(), true)?;
+    let left = unsafe { copy_user_raw(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left != 0 {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+// Read one plain-data value (an integer, a pointer, a repr(C) struct) from user memory.
+pub fn get_user<T: Copy>(addr: u64) -> KResult<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+    };
+    copy_from_user(bytes, addr)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+pub fn put_user<T: Copy>(addr: u64, value: T) -> KResult<()> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&value as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    copy_to_user(addr, bytes)
+}
+
+// Copy a NUL-terminated string of fewer than `max_len` bytes out of user memory. Reads stop at
+// page boundaries so an unmapped page after the terminator does not fail the copy.
+pub fn strncpy_from_user(src: u64, max_len: usize) -> KResult<String> {
+    let mut bytes = Vec::new();
+    let mut addr = src;
+
+    loop {
+        let page_left = (PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize;
+        let chunk_len = page_left.min(max_len - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + chunk_len, 0);
+        copy_from_user(&mut bytes[start..], addr)?;
+
+        if let Some(nul) = bytes[start..].iter().position(|&b| b == 0) {
+            bytes.truncate(start + nul);
+            break;
+        }
+        if bytes.len() >= max_len {
+            return Err(Errno::ENAMETOOLONG);
+        }
+        addr += chunk_len as u64;
+    }
+
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
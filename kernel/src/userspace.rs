@@ -1,245 +1,279 @@
-use crate::gdt::SELECTORS;
+use crate::elf;
+use crate::errno::{Errno, KResult};
+use crate::fs::namei;
+use crate::fs::vfs;
+use crate::hcf;
+use crate::klog;
 use crate::memory;
-use crate::memory::USERSPACE_CODE_START;
-use crate::task::Task;
-use core::arch::asm;
+use crate::scheduler;
+use crate::task::{self, Task};
+use crate::types::FMode;
+use crate::vma::{prot_page_flags, Vma, VmaKind, PROT_READ, PROT_WRITE};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, Page, Size4KiB, Translate,
 };
 use x86_64::VirtAddr;
 
-pub fn jump_userspace(frame_allocator: &mut impl FrameAllocator<Size4KiB>, task: &mut Task) -> () {
-    let mapper = &mut task.page_table;
-    let user_stack_frame = frame_allocator
-        .allocate_frame()
-        .expect("no more frames available");
-    let user_code_frame = frame_allocator
-        .allocate_frame()
-        .expect("no more frames available");
-
-    task.phys_pages.push(PhysFrame::from(user_stack_frame));
-    task.phys_pages.push(PhysFrame::from(user_code_frame));
-
-    let user_stack_flags = PageTableFlags::PRESENT
-        | PageTableFlags::WRITABLE
-        | PageTableFlags::USER_ACCESSIBLE
-        | PageTableFlags::NO_EXECUTE;
-    let user_stack_start = VirtAddr::new(memory::USERSPACE_STACK_START);
-    let user_stack_page = Page::containing_address(user_stack_start);
-    unsafe {
-        mapper
-            .map_to(
-                user_stack_page,
-                user_stack_frame,
-                user_stack_flags,
-                frame_allocator,
-            )
-            .expect("map_to failed")
-            .flush();
+// Read a whole file out of the VFS, e.g. an executable that is about to be loaded.
+pub fn read_file_contents(path: &str) -> KResult<Vec<u8>> {
+    let _vfs = vfs::lock();
+    let dentry = namei::path_lookup(path, namei::LOOKUP_FOLLOW)?;
+
+    let size = unsafe {
+        let inode = (*dentry).d_inode;
+        if inode.is_null() {
+            return Err(Errno::ENOENT);
+        }
+        if vfs::is_dir(inode) {
+            return Err(Errno::EISDIR);
+        }
+        (*inode).i_size as usize
+    };
+
+    let mut file = vfs::open_file(dentry, FMode::from(vfs::FMODE_READ))?;
+    let mut data = vec![0u8; size];
+    let mut read = 0;
+    while read < size {
+        match vfs::read_file(&mut file, &mut data[read..]) {
+            Ok(0) => break,
+            Ok(count) => read += count,
+            Err(err) => {
+                vfs::close_file(file);
+                return Err(err);
+            }
+        }
+    }
+    vfs::close_file(file);
+
+    if read != size {
+        return Err(Errno::EIO);
+    }
+
+    Ok(data)
+}
+
+// Auxiliary vector entry types handed to the program on its initial stack.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+const AUXV_ENTRIES: usize = 7;
+
+// Read the executable at `path` and check that it can be loaded with `argv` and `envp`, so that
+// exec fails before the old image is torn down.
+pub fn read_program(path: &str, argv: &[String], envp: &[String]) -> KResult<Vec<u8>> {
+    let data = read_file_contents(path)?;
+    elf::check_image(&data)?;
+    if initial_stack_size(argv, envp) > (memory::USER_STACK_PAGES - 1) * 4096 {
+        return Err(Errno::E2BIG);
     }
+    Ok(data)
+}
+
+// Replace the calling task's user address space with the executable `data` read by
+// `read_program`. On success returns the entry point and the initial stack pointer, with
+// argc/argv/envp/auxv laid out per the System V ABI. The old image is gone by the time this
+// fails, which only happens when memory runs out.
+pub fn load_program(
+    path: &str,
+    data: &[u8],
+    argv: &[String],
+    envp: &[String],
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> KResult<(u64, u64)> {
+    // The new image is written through the physical mapping, so none of this faults.
+    let (entry, user_rsp) = task::with_current_task(|task| {
+        task.release_user_memory(frame_allocator);
+
+        let image = elf::load_elf(task, data, frame_allocator)?;
+        task.brk_start = (image.end + 4095) & !4095;
+        task.brk = task.brk_start;
+        map_user_stack(task, frame_allocator)?;
+        let user_rsp = build_initial_stack(task, &image, argv, envp)?;
+        Ok((image.entry, user_rsp))
+    })
+    .unwrap_or(Err(Errno::ESRCH))?;
+
+    klog!(Debug, "Loaded {}, entry point {:#x}", path, entry);
+    Ok((entry, user_rsp))
+}
 
-    let user_code_flags =
-        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-    let user_code_start = VirtAddr::new(USERSPACE_CODE_START);
-    let user_code_page = Page::containing_address(user_code_start);
-    unsafe {
-        mapper
-            .map_to(
-                user_code_page,
-                user_code_frame,
-                user_code_flags,
-                frame_allocator,
-            )
-            .expect("map_to failed")
-            .flush();
+fn map_user_stack(
+    task: &mut Task,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> KResult<()> {
+    task.vmas.insert(Vma {
+        start: memory::USER_STACK_TOP - memory::USER_STACK_MAX_SIZE,
+        end: memory::USER_STACK_TOP,
+        prot: PROT_READ | PROT_WRITE,
+        kind: VmaKind::Stack,
+        shared: false,
+        file: None,
+        file_offset: 0,
+    })?;
+
+    let flags = prot_page_flags(PROT_READ | PROT_WRITE);
+
+    for i in 0..memory::USER_STACK_PAGES {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+            memory::USERSPACE_STACK_START - i * 4096,
+        ));
+        let frame = frame_allocator.allocate_frame().ok_or(Errno::ENOMEM)?;
+        unsafe {
+            core::ptr::write_bytes(
+                (memory::PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8,
+                0,
+                4096,
+            );
+            task.page_table
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(|_| Errno::ENOMEM)?
+                .flush();
+        }
+        task.phys_pages.push(frame);
     }
 
-    let fn_size = 0x800;
-    let userspace_fn = user_code_start.as_u64() as *mut u8;
-    unsafe {
-        core::ptr::copy_nonoverlapping(test_userspace_routine as *const _, userspace_fn, fn_size);
+    Ok(())
+}
+
+// Copy `bytes` to `addr` in the task's address space through the physical memory mapping.
+fn write_user(task: &Task, addr: u64, bytes: &[u8]) -> KResult<()> {
+    let mut written = 0;
+    while written < bytes.len() {
+        let virt = VirtAddr::new(addr + written as u64);
+        let phys = task.page_table.translate_addr(virt).ok_or(Errno::EFAULT)?;
+        let chunk = (4096 - (virt.as_u64() & 0xFFF) as usize).min(bytes.len() - written);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr().add(written),
+                (memory::PHYSICAL_MEMORY_OFFSET + phys.as_u64()) as *mut u8,
+                chunk,
+            );
+        }
+        written += chunk;
     }
+    Ok(())
+}
 
-    let user_stack_pointer = user_stack_page.start_address().as_u64() + 4096 - 2048;
-
-    unsafe {
-        asm!(
-        "cli",
-        "push {user_ds}",
-        "push {user_sp}",
-        "pushfq",
-        "pop rax",
-        "or rax, 0x200",
-        "push rax",
-        "push {user_cs}",
-        "push {user_rip}",
-        "iretq",
-
-        user_ds = in(reg) SELECTORS.user_data_selector.0 as u64,
-        user_cs = in(reg) SELECTORS.user_code_selector.0 as u64,
-        user_sp = in(reg) user_stack_pointer,
-        user_rip = in(reg) user_code_start.as_u64(),
-        );
+// Not cryptographically strong, but good enough to seed stack protectors and hash tables.
+fn random_bytes() -> [u8; 16] {
+    let mut state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    let mut bytes = [0u8; 16];
+    for chunk in bytes.chunks_mut(8) {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes());
     }
+    bytes
+}
+
+// Bytes `build_initial_stack` needs at most: the strings, AT_RANDOM's bytes, argc, both pointer
+// arrays with their terminators and the auxiliary vector, plus room for alignment.
+fn initial_stack_size(argv: &[String], envp: &[String]) -> u64 {
+    let strings: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * AUXV_ENTRIES;
+    strings + 16 + words as u64 * 8 + 32
 }
 
-#[no_mangle]
-pub extern "C" fn test_userspace_routine() {
-    unsafe {
-        asm!(
-            // Allocate space on stack for strings and buffers
-            "sub rsp, 256",
-            // First, write a message to stdout
-            "lea rsi, [rsp + 200]",
-            "mov byte ptr [rsi], 'T'",
-            "mov byte ptr [rsi + 1], 'e'",
-            "mov byte ptr [rsi + 2], 's'",
-            "mov byte ptr [rsi + 3], 't'",
-            "mov byte ptr [rsi + 4], 'i'",
-            "mov byte ptr [rsi + 5], 'n'",
-            "mov byte ptr [rsi + 6], 'g'",
-            "mov byte ptr [rsi + 7], ' '",
-            "mov byte ptr [rsi + 8], 'f'",
-            "mov byte ptr [rsi + 9], 'i'",
-            "mov byte ptr [rsi + 10], 'l'",
-            "mov byte ptr [rsi + 11], 'e'",
-            "mov byte ptr [rsi + 12], ' '",
-            "mov byte ptr [rsi + 13], 'o'",
-            "mov byte ptr [rsi + 14], 'p'",
-            "mov byte ptr [rsi + 15], 's'",
-            "mov byte ptr [rsi + 16], '!'",
-            "mov byte ptr [rsi + 17], 10", // newline
-            "mov rax, 1",                  // sys_write
-            "mov rdi, 1",                  // stdout
-            "mov rdx, 18",                 // length
-            "syscall",
-            // Prepare path string "/dummy.txt" at rsp+100
-            "lea rbx, [rsp + 100]",
-            "mov byte ptr [rbx], '/'",
-            "mov byte ptr [rbx + 1], 'd'",
-            "mov byte ptr [rbx + 2], 'u'",
-            "mov byte ptr [rbx + 3], 'm'",
-            "mov byte ptr [rbx + 4], 'm'",
-            "mov byte ptr [rbx + 5], 'y'",
-            "mov byte ptr [rbx + 6], '.'",
-            "mov byte ptr [rbx + 7], 't'",
-            "mov byte ptr [rbx + 8], 'x'",
-            "mov byte ptr [rbx + 9], 't'",
-            "mov byte ptr [rbx + 10], 0", // null terminator
-            // sys_open("/dummy.txt", O_RDONLY=0, 0)
-            "mov rax, 2",   // sys_open
-            "mov rdi, rbx", // pathname
-            "mov rsi, 0",   // O_RDONLY
-            "mov rdx, 0",   // mode
-            "syscall",
-            // Check if open succeeded (fd >= 3)
-            "cmp rax, 3",
-            "jl 2f", // jump to label 2 (open_failed)
-            // Save fd in r12
-            "mov r12, rax",
-            // Read from file into buffer at rsp+150
-            "mov rax, 0",           // sys_read
-            "mov rdi, r12",         // fd
-            "lea rsi, [rsp + 150]", // buffer
-            "mov rdx, 64",          // count
-            "syscall",
-            // Save read count in r13
-            "mov r13, rax",
-            // Write what we read to stdout
-            "mov rax, 1",           // sys_write
-            "mov rdi, 1",           // stdout
-            "lea rsi, [rsp + 150]", // buffer
-            "mov rdx, r13",         // count
-            "syscall",
-            // Write a newline
-            "lea rsi, [rsp + 200]",
-            "mov byte ptr [rsi], 10",
-            "mov rax, 1",
-            "mov rdi, 1",
-            "mov rdx, 1",
-            "syscall",
-            // Close the file
-            "mov rax, 3",   // sys_close
-            "mov rdi, r12", // fd
-            "syscall",
-            // Now test writing to the file
-            "mov rax, 2",   // sys_open
-            "mov rdi, rbx", // pathname
-            "mov rsi, 1",   // O_WRONLY
-            "mov rdx, 0",   // mode
-            "syscall",
-            "cmp rax, 3",
-            "jl 2f",        // jump to label 2 (write_test_failed)
-            "mov r12, rax", // save fd
-            // Write test data
-            "lea rsi, [rsp + 200]",
-            "mov byte ptr [rsi], 'W'",
-            "mov byte ptr [rsi + 1], 'r'",
-            "mov byte ptr [rsi + 2], 'o'",
-            "mov byte ptr [rsi + 3], 't'",
-            "mov byte ptr [rsi + 4], 'e'",
-            "mov byte ptr [rsi + 5], ' '",
-            "mov byte ptr [rsi + 6], 'f'",
-            "mov byte ptr [rsi + 7], 'r'",
-            "mov byte ptr [rsi + 8], 'o'",
-            "mov byte ptr [rsi + 9], 'm'",
-            "mov byte ptr [rsi + 10], ' '",
-            "mov byte ptr [rsi + 11], 'u'",
-            "mov byte ptr [rsi + 12], 's'",
-            "mov byte ptr [rsi + 13], 'e'",
-            "mov byte ptr [rsi + 14], 'r'",
-            "mov byte ptr [rsi + 15], 's'",
-            "mov byte ptr [rsi + 16], 'p'",
-            "mov byte ptr [rsi + 17], 'a'",
-            "mov byte ptr [rsi + 18], 'c'",
-            "mov byte ptr [rsi + 19], 'e'",
-            "mov byte ptr [rsi + 20], '!'",
-            "mov byte ptr [rsi + 21], 10",
-            "mov rax, 1",   // sys_write
-            "mov rdi, r12", // fd
-            "mov rdx, 22",  // count
-            "syscall",
-            // Close file
-            "mov rax, 3", // sys_close
-            "mov rdi, r12",
-            "syscall",
-            // Reopen for reading to verify the write
-            "mov rax, 2",   // sys_open
-            "mov rdi, rbx", // pathname
-            "mov rsi, 0",   // O_RDONLY
-            "mov rdx, 0",   // mode
-            "syscall",
-            "cmp rax, 3",
-            "jl 3f",        // skip read if open failed
-            "mov r12, rax", // save fd
-            // Read back what we wrote (seek to end - 22, or just read from start)
-            // For simplicity, read from start
-            "mov rax, 0",          // sys_read
-            "mov rdi, r12",        // fd
-            "lea rsi, [rsp + 50]", // different buffer
-            "mov rdx, 100",        // count
-            "syscall",
-            // Write what we read to stdout
-            "mov r13, rax",        // save read count
-            "mov rax, 1",          // sys_write
-            "mov rdi, 1",          // stdout
-            "lea rsi, [rsp + 50]", // buffer
-            "mov rdx, r13",        // count
-            "syscall",
-            // Close file
-            "mov rax, 3", // sys_close
-            "mov rdi, r12",
-            "syscall",
-            "jmp 3f", // jump to label 3 (done)
-            "2:",     // open_failed / write_test_failed
-            "3:",     // done
-            // Exit
-            "mov rax, 60", // sys_exit
-            "mov rdi, 0",  // exit code
-            "syscall",
-            "4:",
-            "jmp 4b",
-        );
+fn build_initial_stack(
+    task: &Task,
+    image: &elf::ElfImage,
+    argv: &[String],
+    envp: &[String],
+) -> KResult<u64> {
+    let stack_top = memory::USER_STACK_TOP;
+    let stack_limit = stack_top - memory::USER_STACK_PAGES * 4096;
+    let mut sp = stack_top;
+
+    let push_string = |s: &str, sp: &mut u64| -> KResult<u64> {
+        let len = s.len() as u64 + 1;
+        if *sp - stack_limit < len + 4096 {
+            return Err(Errno::E2BIG);
+        }
+        *sp -= len;
+        write_user(task, *sp, s.as_bytes())?;
+        write_user(task, *sp + s.len() as u64, &[0])?;
+        Ok(*sp)
+    };
+
+    let mut argv_ptrs = Vec::with_capacity(argv.len());
+    for arg in argv {
+        argv_ptrs.push(push_string(arg, &mut sp)?);
+    }
+    let mut envp_ptrs = Vec::with_capacity(envp.len());
+    for env in envp {
+        envp_ptrs.push(push_string(env, &mut sp)?);
+    }
+
+    sp -= 16;
+    let random_addr = sp;
+    write_user(task, random_addr, &random_bytes())?;
+
+    let auxv: [(u64, u64); AUXV_ENTRIES] = [
+        (AT_PHDR, image.phdr_addr),
+        (AT_PHENT, image.phentsize),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, image.entry),
+        (AT_RANDOM, random_addr),
+        (AT_NULL, 0),
+    ];
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // rsp must be 16-byte aligned at the entry point, pointing at argc.
+    sp &= !0xF;
+    sp -= words.len() as u64 * 8;
+    sp &= !0xF;
+    if sp < stack_limit + 4096 {
+        return Err(Errno::E2BIG);
+    }
+
+    let mut bytes = Vec::with_capacity(words.len() * 8);
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
     }
-    loop {}
+    write_user(task, sp, &bytes)?;
+
+    Ok(sp)
+}
+
+pub fn jump_userspace(
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    path: &str,
+) -> ! {
+    let argv = [String::from(path)];
+    let loaded = read_program(path, &argv, &[])
+        .and_then(|data| load_program(path, &data, &argv, &[], frame_allocator));
+    let (entry, user_rsp) = match loaded {
+        Ok(result) => result,
+        Err(err) => {
+            klog!(Fatal, "Failed to load {}: {:?}", path, err);
+            hcf::hcf();
+        }
+    };
+
+    // The task enters ring 3 the first time the scheduler picks it.
+    task::with_current_task(|task| task.prepare_user_entry(entry, user_rsp));
+    // Give up the task's identity before queueing it: another CPU may pick it up right away.
+    let pid = task::getpid();
+    task::set_current_pid(scheduler::IDLE_PID);
+    scheduler::add_task(pid);
+    scheduler::run();
 }
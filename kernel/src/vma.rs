@@ -0,0 +1,193 @@
+use crate::errno::{Errno, KResult};
+use crate::fs::file::OpenFile;
+use crate::instructions;
+use crate::memory::COW_FLAG;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+// Page table flags for a user page with protection `prot`. PROT_NONE pages stay present but lose
+// USER_ACCESSIBLE, so they keep their frame and can be made accessible again by mprotect.
+pub fn prot_page_flags(prot: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if prot != 0 {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    // Without EFER.NXE the bit is reserved and would make every access fault.
+    if prot & PROT_EXEC == 0 && instructions::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    // A PT_LOAD segment of the executable; pages past the file data are zero-filled on demand.
+    Image,
+    Stack,
+    // The brk heap.
+    Heap,
+    // mmap(MAP_ANONYMOUS)
+    Anonymous,
+    // mmap of a file; the pages within the file are the file's own frames.
+    File,
+}
+
+// A page-aligned range [start, end) of a task's address space that may be touched. Pages inside
+// it need not be mapped yet; the page fault handler maps them on first access.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub prot: u32,
+    pub kind: VmaKind,
+    // MAP_SHARED: fork shares the pages writable instead of copy-on-write.
+    pub shared: bool,
+    // For file mappings, the file and the offset in it that `start` maps.
+    pub file: Option<Arc<OpenFile>>,
+    pub file_offset: u64,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn allows(&self, write: bool, exec: bool) -> bool {
+        if write && self.prot & PROT_WRITE == 0 {
+            return false;
+        }
+        if exec && self.prot & PROT_EXEC == 0 {
+            return false;
+        }
+        self.prot != 0
+    }
+
+    pub fn page_flags(&self) -> PageTableFlags {
+        prot_page_flags(self.prot)
+    }
+
+    // Flags for a file page mapped into this area. Private mappings share the file's frames
+    // copy-on-write.
+    pub fn file_page_flags(&self) -> PageTableFlags {
+        let mut flags = self.page_flags();
+        if !self.shared && flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COW_FLAG);
+        }
+        flags
+    }
+}
+
+// The VMAs of one address space, keyed by start address. Areas never overlap.
+#[derive(Debug, Clone, Default)]
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        VmaList {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn find_mut(&mut self, addr: u64) -> Option<&mut Vma> {
+        self.areas
+            .range_mut(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> KResult<()> {
+        if vma.start >= vma.end || self.overlaps(vma.start, vma.end) {
+            return Err(Errno::ENOMEM);
+        }
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    // Empty the list, handing back every area.
+    pub fn drain(&mut self) -> Vec<Vma> {
+        core::mem::take(&mut self.areas).into_values().collect()
+    }
+
+    // Cut the area containing `addr` in two, so that an area boundary falls on `addr`.
+    pub fn split_at(&mut self, addr: u64) {
+        if let Some(vma) = self.find(addr).cloned() {
+            if vma.start != addr {
+                let upper = Vma {
+                    start: addr,
+                    file_offset: vma.file_offset + (addr - vma.start),
+                    ..vma.clone()
+                };
+                self.areas.insert(vma.start, Vma { end: addr, ..vma });
+                self.areas.insert(addr, upper);
+            }
+        }
+    }
+
+    // Take [start, end) out of the list, trimming areas that stick out of it. Returns what was
+    // removed.
+    pub fn remove_range(&mut self, start: u64, end: u64) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<u64> = self.areas.range(start..end).map(|(&s, _)| s).collect();
+        starts
+            .into_iter()
+            .filter_map(|s| self.areas.remove(&s))
+            .collect()
+    }
+
+    // Whether every address in [start, end) lies in some area.
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    pub fn range_mut(&mut self, start: u64, end: u64) -> impl Iterator<Item = &mut Vma> {
+        self.areas.range_mut(start..end).map(|(_, vma)| vma)
+    }
+
+    // Start of the highest free range of `len` bytes inside [floor, limit).
+    pub fn find_free(&self, len: u64, floor: u64, limit: u64) -> Option<u64> {
+        let mut top = limit;
+        for vma in self.areas.values().rev() {
+            if vma.end <= top && top - vma.end >= len {
+                break;
+            }
+            top = top.min(vma.start);
+        }
+        top.checked_sub(len).filter(|start| *start >= floor)
+    }
+}